@@ -0,0 +1,72 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::State;
+
+/// The name of the optional project configuration file, which lives next to the entrypoint.
+pub const CONFIG_FILE: &str = "htmlgen.toml";
+
+/// Book-wide settings, read from `CONFIG_FILE`. Everything is optional and falls back to the built-in defaults.
+///
+/// The `hsection_*` arrays are indexed by nesting depth, index zero is never used since `§hsection`s start at level one.
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectConfig {
+    pub domain: Option<String>,
+    /// Relative to the directory of the entrypoint, which it must not contain.
+    pub output_dir: Option<PathBuf>,
+    /// The stylesheet that `§template` pages use instead of `assets/main.css`.
+    pub theme: Option<String>,
+
+    pub hsection_pre_number: Option<[String; 6]>,
+    pub hsection_post_number: Option<[String; 6]>,
+    pub hsection_render_number: Option<[bool; 6]>,
+    pub hsection_name: Option<[String; 6]>,
+
+    pub aside_level: Option<usize>,
+    pub box_exercise_level: Option<usize>,
+    pub box_other_level: Option<usize>,
+}
+
+impl ProjectConfig {
+    pub fn parse(s: &str) -> Result<Self, toml::de::Error> {
+        return toml::from_str(s);
+    }
+
+    // Overwrite the defaults of a fresh `State`.
+    pub(crate) fn apply(&self, state: &mut State) {
+        if let Some(domain) = &self.domain {
+            state.domain = domain.clone();
+        }
+        if let Some(output_dir) = &self.output_dir {
+            state.output_dir = crate::normalize(&state.base_dir().join(output_dir));
+        }
+        if let Some(theme) = &self.theme {
+            state.theme = theme.clone();
+        }
+
+        if let Some(pre) = &self.hsection_pre_number {
+            state.hsection_pre_number = pre.clone();
+        }
+        if let Some(post) = &self.hsection_post_number {
+            state.hsection_post_number = post.clone();
+        }
+        if let Some(render) = self.hsection_render_number {
+            state.hsection_render_number = render;
+        }
+        if let Some(name) = &self.hsection_name {
+            state.hsection_name = name.clone();
+        }
+
+        if let Some(level) = self.aside_level {
+            state.aside_level = level;
+        }
+        if let Some(level) = self.box_exercise_level {
+            state.box_exercise_level = level;
+        }
+        if let Some(level) = self.box_other_level {
+            state.box_other_level = level;
+        }
+    }
+}
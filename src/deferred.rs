@@ -0,0 +1,102 @@
+// Cross references are resolved after expansion: whenever a macro needs information that might only be
+// collected further down the document, it emits a placeholder instead, which gets replaced once the whole
+// document has been expanded and the `StickyState` is complete.
+
+use crate::State;
+use crate::macros::{ExpansionError, Trace, ERROR_PLACEHOLDER};
+
+const START: char = '\u{FDD0}';
+const END: char = '\u{FDD1}';
+
+pub(crate) type Compute = dyn Fn(&State, &mut Resolver) -> Result<String, ExpansionError>;
+
+pub(crate) struct Deferred {
+    pub description: String, // what to report if this takes part in a cycle
+    pub compute: Box<Compute>,
+}
+
+pub(crate) fn placeholder(index: usize) -> String {
+    return format!("{}{}{}", START, index, END);
+}
+
+pub(crate) fn contains_placeholder(s: &str) -> bool {
+    return s.contains(START);
+}
+
+// Sources must not contain the characters that delimit placeholders, or resolving would take them for one.
+pub(crate) fn check_source(s: &str, source_offset: usize) -> Result<(), ExpansionError> {
+    match s.char_indices().find(|(_, c)| *c == START || *c == END) {
+        None => return Ok(()),
+        Some((i, c)) => return Err(ExpansionError::ReservedCharacter(c, Trace(Some((source_offset + i, source_offset + i + c.len_utf8()))))),
+    }
+}
+
+/// Replaces placeholders by their values, computing each value at most once.
+pub(crate) struct Resolver {
+    values: Vec<Option<String>>,
+    in_progress: Vec<usize>,
+    pub errors: Vec<ExpansionError>, // recoverable errors, their values are `ERROR_PLACEHOLDER`
+}
+
+impl Resolver {
+    pub fn new(state: &State) -> Self {
+        return Resolver {
+            values: vec![None; state.deferred.len()],
+            in_progress: Vec::new(),
+            errors: Vec::new(),
+        };
+    }
+
+    pub fn resolve(&mut self, state: &State, s: &str) -> Result<String, ExpansionError> {
+        if !contains_placeholder(s) {
+            return Ok(s.to_string());
+        }
+
+        let mut resolved = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(start) = rest.find(START) {
+            resolved.push_str(&rest[..start]);
+            let after_start = &rest[start + START.len_utf8()..];
+            let end = after_start.find(END).expect("placeholders are never split");
+            let index = after_start[..end].parse().expect("placeholders contain their index");
+            resolved.push_str(&self.value(state, index)?);
+            rest = &after_start[end + END.len_utf8()..];
+        }
+        resolved.push_str(rest);
+
+        return Ok(resolved);
+    }
+
+    // Compute all values, so that errors surface even for placeholders that did not end up in any output.
+    pub fn resolve_all(&mut self, state: &State) -> Result<(), ExpansionError> {
+        for index in 0..state.deferred.len() {
+            self.value(state, index)?;
+        }
+        return Ok(());
+    }
+
+    fn value(&mut self, state: &State, index: usize) -> Result<String, ExpansionError> {
+        if let Some(value) = &self.values[index] {
+            return Ok(value.clone());
+        }
+
+        if let Some(position) = self.in_progress.iter().position(|i| *i == index) {
+            let cycle = self.in_progress[position..].iter().map(|i| state.deferred[*i].description.clone()).collect();
+            return Err(ExpansionError::CyclicReferences(cycle));
+        }
+
+        self.in_progress.push(index);
+        let value = match (state.deferred[index].compute)(state, self) {
+            Ok(raw) => self.resolve(state, &raw)?,
+            Err(e) if e.is_recoverable() => {
+                self.errors.push(e);
+                ERROR_PLACEHOLDER.to_string()
+            }
+            Err(e) => return Err(e),
+        };
+        self.in_progress.pop();
+
+        self.values[index] = Some(value.clone());
+        return Ok(value);
+    }
+}
@@ -0,0 +1,144 @@
+// Errors are turned into `Diagnostic`s, which are then either printed for humans, or emitted as json for tools.
+
+use serde_json::{json, Value};
+
+use crate::macros::Trace;
+use crate::source::SourceMap;
+
+/// How errors are reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// Prose, with the offending source lines underlined.
+    Human,
+    /// One json object per line and error or warning, with the kind, severity, message and the positions of all involved spans.
+    Json,
+}
+
+pub(crate) struct Diagnostic {
+    pub kind: &'static str, // the name of the error or warning variant
+    pub warning: bool, // an error otherwise
+    pub message: String,
+    pub notes: Vec<String>, // further details, printed between the message and the spans
+    pub spans: Vec<(Option<String> /* label */, Trace)>,
+}
+
+impl Diagnostic {
+    pub fn new(kind: &'static str, message: impl Into<String>) -> Self {
+        return Diagnostic {
+            kind,
+            warning: false,
+            message: message.into(),
+            notes: Vec::new(),
+            spans: Vec::new(),
+        };
+    }
+
+    pub fn warning(mut self) -> Self {
+        self.warning = true;
+        return self;
+    }
+
+    pub fn note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        return self;
+    }
+
+    pub fn at(mut self, t: Trace) -> Self {
+        self.spans.push((None, t));
+        return self;
+    }
+
+    pub fn labeled(mut self, label: impl Into<String>, t: Trace) -> Self {
+        self.spans.push((Some(label.into()), t));
+        return self;
+    }
+
+    pub fn print(&self, source: &SourceMap) {
+        println!("{}", self.message);
+        for note in &self.notes {
+            println!("{}", note);
+        }
+        for (label, t) in &self.spans {
+            if let Some(label) = label {
+                println!("{}:", label);
+            }
+            print_trace(t.clone(), source);
+        }
+    }
+
+    // The first span is the primary one, its position is also given at the top level of the object.
+    // Positions are `null` for spans generated by macros.
+    pub fn to_json(&self, source: &SourceMap) -> Value {
+        let spans: Vec<Value> = self.spans.iter().map(|(label, t)| {
+            let mut span = location_json(t, source);
+            span["label"] = json!(label);
+            return span;
+        }).collect();
+
+        let mut diagnostic = match spans.first() {
+            Some(primary) => primary.clone(),
+            None => location_json(&Trace(None), source),
+        };
+        diagnostic.as_object_mut().unwrap().remove("label");
+        diagnostic["kind"] = json!(self.kind);
+        diagnostic["severity"] = json!(if self.warning { "warning" } else { "error" });
+        // The human output spaces things out with whitespace that is of no use here.
+        diagnostic["message"] = json!(self.message.trim());
+        diagnostic["notes"] = json!(self.notes.iter().map(|note| note.trim()).collect::<Vec<_>>());
+        diagnostic["spans"] = json!(spans);
+        return diagnostic;
+    }
+}
+
+fn location_json(t: &Trace, source: &SourceMap) -> Value {
+    let resolved = match t {
+        Trace(Some((start, end))) => source.resolve_span(*start, *end),
+        Trace(None) => None,
+    };
+
+    match resolved {
+        None => return json!({ "file": null, "start": null, "end": null, "included_from": [] }),
+        Some((start, end)) => {
+            let included_from: Vec<Value> = t.0.iter().flat_map(|(offset, _)| source.include_stack(*offset)).filter_map(|include| {
+                let p = source.resolve(include.0?.0)?;
+                return Some(json!({ "file": p.filename, "line": p.line, "column": p.col }));
+            }).collect();
+
+            return json!({
+                "file": start.filename,
+                "start": { "line": start.line, "column": start.col },
+                "end": { "line": end.line, "column": end.col },
+                "included_from": included_from,
+            });
+        }
+    }
+}
+
+// Print where something happened like rustc does: the position, the line with the span underlined, and the `§input`s that led there.
+pub(crate) fn print_trace(t: Trace, source: &SourceMap) {
+    match t {
+        Trace(None) => println!("Generated in macro at unknown location."),
+        Trace(Some((start, end))) => match source.resolve(start) {
+            None => println!("At an unknown location."),
+            Some(position) => {
+                let gutter = " ".repeat(position.line.to_string().len());
+                println!("{}--> {}:{}:{}", gutter, position.filename, position.line, position.col);
+                println!("{} |", gutter);
+                println!("{} | {}", position.line, position.line_text);
+
+                // Keep tabs so that the carets line up, only underline the first line of spans that cover several.
+                let indentation: String = position.line_text.chars().take(position.col - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+                let width = source.contents[start..end].chars().take_while(|c| *c != '\n').count().max(1);
+                println!("{} | {}{}", gutter, indentation, "^".repeat(width));
+
+                for include in source.include_stack(start) {
+                    if let Trace(Some((include_start, _))) = include {
+                        if let Some(p) = source.resolve(include_start) {
+                            println!("{} = included from {}:{}:{}", gutter, p.filename, p.line, p.col);
+                        }
+                    }
+                }
+            }
+        },
+    }
+}
@@ -0,0 +1,268 @@
+// Formatting of .gen files: the source is parsed into a lossless syntax tree, which is then printed again with
+// consistent indentation of macro arguments. Only whitespace at the start and the end of lines changes, everything
+// else (comments, escapes, parameters) is reproduced exactly.
+
+use crate::parse::{OpenParen, ParseError};
+
+const INDENTATION: &str = "  ";
+
+// Macros whose arguments keep all of their whitespace.
+const PREFORMATTED: [&str; 1] = ["pre"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Node {
+    Text(String), // including escapes and the parentheses of text
+    Comment(String), // from `§#` up to, but excluding, the end of the line
+    Macro {
+        name: String,
+        params: String, // verbatim, including the brackets, empty if there are none
+        args: Vec<Vec<Node>>,
+    },
+}
+
+/// Parse the source of a .gen file, keeping everything needed to reproduce it.
+pub(crate) fn parse(s: &str) -> Result<Vec<Node>, ParseError> {
+    let mut p = CstParser { s, position: 0 };
+    return p.nodes(None);
+}
+
+/// Reindent the source of a .gen file.
+pub(crate) fn format(s: &str) -> Result<String, ParseError> {
+    let mut printer = Printer {
+        out: String::with_capacity(s.len()),
+        line_start: true,
+        line_level: 0,
+    };
+    printer.nodes(&parse(s)?, 0);
+    return Ok(printer.out);
+}
+
+/// The exact source that the nodes were parsed from.
+pub(crate) fn source(nodes: &[Node]) -> String {
+    let mut s = String::new();
+    for node in nodes {
+        match node {
+            Node::Text(text) | Node::Comment(text) => s.push_str(text),
+            Node::Macro { name, params, args } => {
+                s.push('§');
+                s.push_str(name);
+                s.push_str(params);
+                for arg in args {
+                    s.push('(');
+                    s.push_str(&source(arg));
+                    s.push(')');
+                }
+            }
+        }
+    }
+    return s;
+}
+
+struct CstParser<'a> {
+    s: &'a str,
+    position: usize,
+}
+
+impl CstParser<'_> {
+    fn rest(&self) -> &str {
+        return &self.s[self.position..];
+    }
+
+    // Parse up to the end of the input, or, inside an argument, up to and including its closing parenthesis.
+    fn nodes(&mut self, argument_of: Option<(usize /* offset of the `(` */, &str)>) -> Result<Vec<Node>, ParseError> {
+        let mut nodes = Vec::new();
+        let mut text = String::new();
+        let mut parens = 0; // open parentheses in the text of this argument
+
+        loop {
+            let rest = self.rest();
+            let c = match rest.chars().next() {
+                Some(c) => c,
+                None => match argument_of {
+                    Some((offset, name)) => return Err(ParseError::Unclosed(OpenParen::argument(offset, name), None)),
+                    None => break,
+                },
+            };
+
+            if c == '§' {
+                let after = &rest['§'.len_utf8()..];
+                if after.starts_with(['§', '(', ')']) {
+                    text.push_str(&rest[..'§'.len_utf8() + 1]);
+                    self.position += '§'.len_utf8() + 1;
+                    continue;
+                }
+
+                if !text.is_empty() {
+                    nodes.push(Node::Text(std::mem::take(&mut text)));
+                }
+                if after.starts_with('#') {
+                    let end = rest.find('\n').unwrap_or(rest.len());
+                    nodes.push(Node::Comment(rest[..end].to_string()));
+                    self.position += end;
+                } else {
+                    nodes.push(self.macro_node()?);
+                }
+                continue;
+            }
+
+            self.position += c.len_utf8();
+            if c == '(' {
+                parens += 1;
+            } else if c == ')' {
+                if parens > 0 {
+                    parens -= 1;
+                } else if argument_of.is_some() {
+                    break;
+                }
+            }
+            text.push(c);
+        }
+
+        if !text.is_empty() {
+            nodes.push(Node::Text(text));
+        }
+        return Ok(nodes);
+    }
+
+    fn macro_node(&mut self) -> Result<Node, ParseError> {
+        self.position += '§'.len_utf8();
+        let rest = self.rest();
+        let name = rest[..rest.find(['§', ' ', '\n', '\t', '\r', '[', '{', '(', ')']).unwrap_or(rest.len())].to_string();
+        self.position += name.len();
+
+        let params_start = self.position;
+        if self.rest().starts_with(['[', '{']) {
+            self.skip_params()?;
+        }
+        let params = self.s[params_start..self.position].to_string();
+
+        let mut args = Vec::new();
+        while self.rest().starts_with('(') {
+            let offset = self.position;
+            self.position += 1;
+            args.push(self.nodes(Some((offset, &name)))?);
+        }
+
+        return Ok(Node::Macro { name, params, args });
+    }
+
+    // Skip over balanced brackets, minding strings.
+    fn skip_params(&mut self) -> Result<(), ParseError> {
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+
+        for (i, c) in self.rest().char_indices() {
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    in_string = false;
+                }
+                continue;
+            }
+
+            match c {
+                '"' => in_string = true,
+                '[' | '{' => depth += 1,
+                ']' | '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.position += i + 1;
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        return Err(ParseError::Eoi);
+    }
+}
+
+struct Printer {
+    out: String,
+    line_start: bool, // nothing but indentation on the current line yet
+    line_level: usize, // indentation level of the current line
+}
+
+impl Printer {
+    // The contents of an argument are indented one level deeper than the line that opens the argument.
+    fn nodes(&mut self, nodes: &[Node], level: usize) {
+        let baseline = text_baseline(nodes);
+
+        for node in nodes {
+            match node {
+                Node::Text(text) => self.text(text, level, baseline),
+                Node::Comment(comment) => self.content(comment, level, ""),
+                Node::Macro { name, params, args } => {
+                    self.content(&format!("§{}{}", name, params), level, "");
+                    let open_level = self.line_level;
+
+                    for arg in args {
+                        self.out.push('(');
+                        if PREFORMATTED.contains(&name.as_str()) {
+                            self.out.push_str(&source(arg));
+                            self.line_start = false;
+                        } else {
+                            self.nodes(arg, open_level + 1);
+                        }
+                        // A `)` on a line of its own lines up with the line that opened the argument.
+                        self.content(")", open_level, "");
+                    }
+                }
+            }
+        }
+    }
+
+    // Lines of text keep their indentation relative to the least indented line of text of the same argument,
+    // so that nested markup stays readable.
+    fn text(&mut self, text: &str, level: usize, baseline: usize) {
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                let trimmed = self.out.trim_end_matches([' ', '\t']).len();
+                self.out.truncate(trimmed);
+                self.out.push('\n');
+                self.line_start = true;
+            }
+
+            if self.line_start {
+                let content = line.trim_start_matches([' ', '\t']);
+                if !content.is_empty() {
+                    let indentation = &line[..line.len() - content.len()];
+                    self.content(content, level, indentation.get(baseline..).unwrap_or(""));
+                }
+            } else if !line.is_empty() {
+                self.content(line, level, "");
+            }
+        }
+    }
+
+    fn content(&mut self, s: &str, level: usize, extra_indentation: &str) {
+        if self.line_start {
+            self.out.push_str(&INDENTATION.repeat(level));
+            self.out.push_str(extra_indentation);
+            self.line_start = false;
+            self.line_level = level;
+        }
+        self.out.push_str(s);
+    }
+}
+
+// The smallest indentation of the lines that start with text, among the given nodes.
+fn text_baseline(nodes: &[Node]) -> usize {
+    let mut baseline = usize::MAX;
+    for node in nodes {
+        if let Node::Text(text) = node {
+            for line in text.split('\n').skip(1) {
+                let content = line.trim_start_matches([' ', '\t']);
+                if !content.is_empty() {
+                    baseline = baseline.min(line.len() - content.len());
+                }
+            }
+        }
+    }
+    return baseline;
+}
@@ -1,7 +1,6 @@
 #[derive(Clone, Debug)]
 pub struct HSections {
     structure: HStructure,
-    finished_structure: HStructure,
     current_path: Vec<usize>,
 }
 
@@ -12,24 +11,11 @@ impl HSections {
                 id: "".to_string(),
                 children: Vec::new(),
             },
-            finished_structure: HStructure {
-                id: "".to_string(),
-                children: Vec::new(),
-            },
             current_path: Vec::new(),
         }
     }
 
-    pub fn reset(&mut self) {
-        self.current_path = Vec::new();
-        self.finished_structure = self.structure.clone();
-        self.structure = HStructure {
-            id: "".to_string(),
-            children: Vec::new(),
-        };
-    }
-
-    pub fn push(&mut self, id: String, second_iteration: bool) {
+    pub fn push(&mut self, id: String) {
         let current_hstructure = self.structure.path_to_current(&self.current_path);
         self.current_path.push(current_hstructure.children.len());
 
@@ -43,9 +29,22 @@ impl HSections {
         self.current_path.pop();
     }
 
-    pub fn previous_and_next_ids(&self) -> (Option<&str>, Option<&str>) {
-        let path_previous = self.finished_structure.path_to_previous_sibling(&self.current_path);
-        let path_next = self.finished_structure.path_to_next_sibling(&self.current_path);
+    pub fn current_path(&self) -> Vec<usize> {
+        return self.current_path.clone();
+    }
+
+    // The ids of the hsections directly inside the one at the given path, only complete once the whole document has been expanded.
+    pub fn children(&self, path: &[usize]) -> Vec<&str> {
+        match self.structure.path_to(path) {
+            None => return Vec::new(),
+            Some(s) => return s.children.iter().map(|child| child.id.as_str()).collect(),
+        }
+    }
+
+    // Only meaningful once the whole document has been expanded.
+    pub fn previous_and_next_ids(&self, path: &[usize]) -> (Option<&str>, Option<&str>) {
+        let path_previous = self.structure.path_to_previous_sibling(path);
+        let path_next = self.structure.path_to_next_sibling(path);
         return (path_previous, path_next);
     }
 }
@@ -65,6 +64,14 @@ impl HStructure {
         }
     }
 
+    fn path_to(&self, p: &[usize]) -> Option<&Self> {
+        if p.is_empty() {
+            return Some(self);
+        } else {
+            return self.children.get(p[0])?.path_to(&p[1..]);
+        }
+    }
+
     fn path_to_previous_sibling(&self, p: &[usize]) -> Option<&str> {
         if p.len() == 0 {
             return Some(&self.id);
@@ -0,0 +1,157 @@
+// Just enough of an html tokenizer to inspect the generated files: the tags and their attributes. Text, comments and
+// doctypes are skipped, and so is the content of `script` and `style` elements.
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Tag {
+    Start {
+        name: String, // lowercase
+        attributes: Vec<(String /* lowercase */, String /* with character references decoded */)>,
+        self_closing: bool,
+        offset: usize, // of the `<`
+    },
+    End {
+        name: String,
+        offset: usize,
+    },
+}
+
+impl Tag {
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        match self {
+            Tag::Start { attributes, .. } => return attributes.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str()),
+            Tag::End { .. } => return None,
+        }
+    }
+}
+
+/// All tags of the given html, in order.
+pub(crate) fn tags(s: &str) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    let mut position = 0;
+
+    while let Some(i) = s[position..].find('<') {
+        let offset = position + i;
+        let rest = &s[offset..];
+
+        if rest.starts_with("<!--") {
+            position = rest.find("-->").map_or(s.len(), |end| offset + end + 3);
+        } else if rest.starts_with("<!") || rest.starts_with("<?") {
+            position = rest.find('>').map_or(s.len(), |end| offset + end + 1);
+        } else if rest.starts_with("</") && rest[2..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let name = tag_name(&rest[2..]);
+            position = rest.find('>').map_or(s.len(), |end| offset + end + 1);
+            tags.push(Tag::End { name, offset });
+        } else if rest[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let name = tag_name(&rest[1..]);
+            let (attributes, self_closing, len) = attributes(&rest[1 + name.len()..]);
+            position = offset + 1 + name.len() + len;
+
+            // Raw text, which may contain anything that looks like a tag.
+            if name == "script" || name == "style" {
+                let close = format!("</{}", name);
+                position = s[position..].to_ascii_lowercase().find(&close).map_or(s.len(), |end| position + end);
+            }
+
+            tags.push(Tag::Start { name, attributes, self_closing, offset });
+        } else {
+            position = offset + 1;
+        }
+    }
+
+    return tags;
+}
+
+fn tag_name(s: &str) -> String {
+    let end = s.find(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>').unwrap_or(s.len());
+    return s[..end].to_ascii_lowercase();
+}
+
+// Parse the attributes of a start tag up to and including its `>`, returns them, whether the tag ends in `/>`,
+// and how many bytes were consumed.
+fn attributes(s: &str) -> (Vec<(String, String)>, bool, usize) {
+    let mut attributes = Vec::new();
+    let mut position = 0;
+
+    loop {
+        let rest = &s[position..];
+        let trimmed = rest.trim_start();
+        position += rest.len() - trimmed.len();
+
+        if trimmed.is_empty() {
+            return (attributes, false, position);
+        } else if trimmed.starts_with("/>") {
+            return (attributes, true, position + 2);
+        } else if trimmed.starts_with('>') {
+            return (attributes, false, position + 1);
+        } else if trimmed.starts_with('/') {
+            position += 1;
+            continue;
+        }
+
+        let name_end = trimmed.find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '>' || c == '/').unwrap_or(trimmed.len()).max(1);
+        let name = trimmed[..name_end].to_ascii_lowercase();
+        position += name_end;
+
+        let rest = &s[position..];
+        let after_name = rest.trim_start();
+        if !after_name.starts_with('=') {
+            attributes.push((name, String::new()));
+            continue;
+        }
+        let value_start = after_name[1..].trim_start();
+        position += rest.len() - value_start.len();
+
+        let (value, len) = match value_start.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => match value_start[1..].find(quote) {
+                Some(end) => (&value_start[1..1 + end], end + 2),
+                None => (&value_start[1..], value_start.len()),
+            },
+            _ => {
+                let end = value_start.find(|c: char| c.is_ascii_whitespace() || c == '>').unwrap_or(value_start.len());
+                (&value_start[..end], end)
+            }
+        };
+        attributes.push((name, decode(value)));
+        position += len;
+    }
+}
+
+// Decode the character references that can reasonably occur in generated attribute values.
+fn decode(s: &str) -> String {
+    if !s.contains('&') {
+        return s.to_string();
+    }
+
+    let mut decoded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        decoded.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        let reference = rest.find(';').map(|end| &rest[1..end]);
+        let c = match reference {
+            Some("amp") => Some('&'),
+            Some("lt") => Some('<'),
+            Some("gt") => Some('>'),
+            Some("quot") => Some('"'),
+            Some("apos") => Some('\''),
+            Some(r) if r.starts_with("#x") || r.starts_with("#X") => u32::from_str_radix(&r[2..], 16).ok().and_then(char::from_u32),
+            Some(r) if r.starts_with('#') => r[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+
+        match (c, reference) {
+            (Some(c), Some(r)) => {
+                decoded.push(c);
+                rest = &rest[r.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+
+    return decoded;
+}
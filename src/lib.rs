@@ -0,0 +1,1621 @@
+use ropey::Rope;
+use std::collections::HashSet;
+use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use thiserror::Error;
+
+pub mod macros;
+use macros::*;
+
+pub mod parse;
+use parse::*;
+
+pub mod vfs;
+use vfs::*;
+
+mod hsections;
+use hsections::*;
+
+mod set_examples;
+pub use set_examples::{Operator, Term, S1, S2, S3};
+
+mod serve;
+pub use serve::serve;
+
+mod lsp;
+pub use lsp::lsp;
+
+mod lint;
+use lint::Warning;
+pub use lint::lint;
+
+mod config;
+pub use config::{ProjectConfig, CONFIG_FILE};
+
+mod deferred;
+use deferred::*;
+
+mod source;
+use source::SourceMap;
+
+mod tex;
+use tex::{TexCache, TexJob};
+
+mod diagnostic;
+use diagnostic::Diagnostic;
+pub use diagnostic::MessageFormat;
+
+mod fmt;
+
+mod html;
+mod links;
+mod validate;
+
+pub struct RunConfiguration {
+    pub entrypoint: PathBuf,
+    pub macros: MacroRegistry,
+    pub fs: Box<dyn FileSystem>,
+    /// Use this domain for all absolute urls, ignoring `§set_domain` and the project config.
+    pub domain: Option<String>,
+    /// Where to write all output, relative to the directory of the entrypoint, which it must not contain. Takes precedence over the project config, defaults to `build`.
+    pub output_dir: Option<PathBuf>,
+    /// Add a script to every `§template` page that reloads it whenever `serve` finishes a rebuild.
+    pub live_reload: bool,
+    /// Warn about malformed html in the outputs: unbalanced tags, duplicate ids, and elements that cannot be nested.
+    pub validate_html: bool,
+    pub verbosity: Verbosity,
+    pub message_format: MessageFormat,
+}
+
+/// How much `run`, `watch` and `serve` print besides errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only errors.
+    Quiet,
+    /// Errors and progress messages.
+    Normal,
+    /// Additionally every file that gets written or copied.
+    Verbose,
+}
+
+/// Build once, returns whether the build succeeded.
+pub fn run(c: RunConfiguration) -> bool {
+    let mut y = Yatt::new(c).unwrap();
+
+    match do_run(&mut y) {
+        Err(e) => {
+            print_yatt_error(&e, &y.source, y.message_format);
+            return false;
+        }
+        Ok(_) => {
+            print_build_warnings(&y);
+            if y.verbosity >= Verbosity::Normal {
+                print_changes(&y, false);
+                println!("Build finished.");
+            }
+            return true;
+        }
+    }
+}
+
+/// Expand everything, render all math and resolve all cross references, and report errors like `run`, but without
+/// writing any files: neither outputs and previews, nor copies, nor the TeX cache. Returns whether there were no errors.
+pub fn check(mut c: RunConfiguration) -> bool {
+    c.fs = Box::new(DryRunFs::new(c.fs));
+    // The build itself would claim to write and copy files, only list what would change instead.
+    let verbosity = c.verbosity;
+    c.verbosity = verbosity.min(Verbosity::Normal);
+    let mut y = Yatt::new(c).unwrap();
+
+    match do_run(&mut y) {
+        Err(e) => {
+            print_yatt_error(&e, &y.source, y.message_format);
+            return false;
+        }
+        Ok(_) => {
+            print_build_warnings(&y);
+            if verbosity >= Verbosity::Verbose {
+                print_changes(&y, true);
+            }
+            if verbosity >= Verbosity::Normal {
+                println!("Check finished, no errors.");
+            }
+            return true;
+        }
+    }
+}
+
+/// Delete the output directory of the given entrypoint, returns whether that succeeded.
+///
+/// Refuses to delete anything if the output directory contains a source of the build: the entrypoint, the project
+/// config, an `§input` file or a `§copy` source.
+pub fn clean(mut c: RunConfiguration) -> bool {
+    // Find the sources with a build that writes nothing, its errors do not matter for finding the output directory.
+    let fs: Rc<dyn FileSystem> = Rc::from(c.fs);
+    c.fs = Box::new(DryRunFs::new(Box::new(fs.clone())));
+    let verbosity = c.verbosity;
+    c.verbosity = Verbosity::Quiet;
+    let mut y = Yatt::new(c).unwrap();
+    if let Err(e) = y.configure() {
+        print_yatt_error(&e, &y.source, y.message_format);
+        return false;
+    }
+    let _ = do_run(&mut y);
+    let dir = y.state.output_dir.clone();
+
+    if let Some(source) = y.dependencies.iter().find(|p| p.starts_with(&dir)) {
+        println!("Refusing to remove {}, it contains the source {}.", dir.to_string_lossy(), source.to_string_lossy());
+        return false;
+    }
+
+    match fs.remove_dir_all(&dir) {
+        Ok(()) => {
+            if verbosity >= Verbosity::Verbose {
+                println!("Removed {}", dir.to_string_lossy());
+            }
+            return true;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return true,
+        Err(e) => {
+            println!("Failed to remove {}:\n{}", dir.to_string_lossy(), e);
+            return false;
+        }
+    }
+}
+
+/// Reindent the given .gen files in place, or with `check` only report those that are not formatted yet.
+///
+/// Returns whether all files could be formatted, and with `check`, whether they already were.
+pub fn fmt(fs: Box<dyn FileSystem>, paths: &[PathBuf], check: bool, verbosity: Verbosity) -> bool {
+    let mut success = true;
+
+    for p in paths {
+        let s = match fs.read_to_string(p) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Failed to read {}:\n{}", p.to_string_lossy(), e);
+                success = false;
+                continue;
+            }
+        };
+
+        let formatted = match fmt::format(&s) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                let mut source = SourceMap::new();
+                source.add_file(p, &s, Trace(None));
+                println!("Could not format {}:", p.to_string_lossy());
+                e.diagnostic().print(&source);
+                success = false;
+                continue;
+            }
+        };
+
+        if formatted == s {
+            continue;
+        }
+
+        if check {
+            println!("{} is not formatted.", p.to_string_lossy());
+            success = false;
+        } else if let Err(e) = fs.write(p, &formatted) {
+            println!("Failed to write {}:\n{}", p.to_string_lossy(), e);
+            success = false;
+        } else if verbosity >= Verbosity::Normal {
+            println!("Formatted {}", p.to_string_lossy());
+        }
+    }
+
+    return success;
+}
+
+/// Like `run`, but rebuild whenever the entrypoint, an `§input` file or a `§copy` source changes. Never returns.
+pub fn watch(c: RunConfiguration) -> ! {
+    watch_with(Yatt::new(c).unwrap(), |_| {});
+}
+
+// Rebuild on every change, calling `after_build` with whether the build succeeded.
+fn watch_with(mut y: Yatt, mut after_build: impl FnMut(bool)) -> ! {
+    loop {
+        match do_run(&mut y) {
+            Err(e) => {
+                print_yatt_error(&e, &y.source, y.message_format);
+                after_build(false);
+            }
+            Ok(_) => {
+                print_build_warnings(&y);
+                if y.verbosity >= Verbosity::Normal {
+                    print_changes(&y, false);
+                    println!("Build finished.");
+                }
+                after_build(true);
+            }
+        }
+
+        let dependencies = y.dependencies.clone();
+        let times = modification_times(&dependencies);
+        while modification_times(&dependencies) == times {
+            std::thread::sleep(Duration::from_millis(300));
+        }
+
+        if y.verbosity >= Verbosity::Normal {
+            println!("Change detected, rebuilding.");
+        }
+        y.reset();
+    }
+}
+
+// The warnings of a successful build. Quiet builds only print errors, unless tools read the warnings as json.
+fn print_build_warnings(y: &Yatt) {
+    if y.verbosity >= Verbosity::Normal || y.message_format == MessageFormat::Json {
+        lint::print_warnings(&y.state.warnings, &y.source, y.message_format);
+    }
+}
+
+// List the output files whose content differs from that of the previous build, or, for a `check`, those that a build would change.
+fn print_changes(y: &Yatt, dry_run: bool) {
+    let changed: Vec<&PathBuf> = y.state.outputs.iter().filter(|(_, changed)| **changed).map(|(p, _)| p).collect();
+    let (did, are) = if dry_run { ("would change", "would be") } else { ("changed", "are") };
+
+    if changed.is_empty() {
+        println!("No output file {}.", did);
+    } else if changed.len() == y.state.outputs.len() {
+        println!("All {} output files {} new or changed.", changed.len(), are);
+    } else {
+        println!("{} of {} output files {} new or changed:", changed.len(), y.state.outputs.len(), are);
+        for p in changed {
+            println!("  {}", p.strip_prefix(&y.state.output_dir).unwrap_or(p).to_string_lossy());
+        }
+    }
+}
+
+// The modification times of all given paths, including everything inside of directories.
+fn modification_times(paths: &BTreeSet<PathBuf>) -> BTreeMap<PathBuf, Option<SystemTime>> {
+    fn add(p: &Path, times: &mut BTreeMap<PathBuf, Option<SystemTime>>) {
+        times.insert(p.to_path_buf(), std::fs::metadata(p).and_then(|m| m.modified()).ok());
+
+        if let Ok(entries) = std::fs::read_dir(p) {
+            for entry in entries.flatten() {
+                add(&entry.path(), times);
+            }
+        }
+    }
+
+    let mut times = BTreeMap::new();
+    for p in paths {
+        add(p, &mut times);
+    }
+    return times;
+}
+
+/// The path under which `render` makes its source text available.
+pub const RENDER_ENTRYPOINT: &str = "entry.gen";
+
+/// The result of `render`.
+pub struct Rendered {
+    /// The expansion of the entrypoint itself.
+    pub expanded: Rope,
+    /// All files produced by `§output`, `§copy` and previews, relative to the output directory.
+    pub outputs: BTreeMap<PathBuf, String>,
+}
+
+/// Why `render` failed.
+pub struct RenderError {
+    error: Box<YattError>,
+    source: SourceMap,
+}
+
+impl RenderError {
+    pub fn print(&self) {
+        print_yatt_error(&self.error, &self.source, MessageFormat::Human);
+    }
+}
+
+/// Render the given source text in a `MemoryFs`, without touching the file system or the working directory.
+///
+/// `§input` and `§copy` resolve against `files`, whose keys are relative paths (the source itself is available as `RENDER_ENTRYPOINT`).
+pub fn render(source: &str, files: HashMap<PathBuf, String>, macros: MacroRegistry) -> Result<Rendered, RenderError> {
+    let fs = MemoryFs::from_files(files);
+    let _ = fs.write(RENDER_ENTRYPOINT.as_ref(), source);
+
+    let mut y = Yatt {
+        state: State::new(RENDER_ENTRYPOINT.into(), Rc::new(fs.clone())),
+        source: SourceMap::new(),
+        macros,
+        dependencies: BTreeSet::new(),
+        config: ProjectConfig::default(),
+        domain: None,
+        output_dir: None,
+        live_reload: false,
+        validate_html: false,
+        verbosity: Verbosity::Quiet,
+        message_format: MessageFormat::Human,
+    };
+
+    match do_run(&mut y) {
+        Err(error) => return Err(RenderError { error: Box::new(error), source: y.source }),
+        Ok(expanded) => {
+            let outputs = fs.files().into_iter().filter_map(|(path, content)| {
+                path.strip_prefix(&y.state.output_dir).ok().map(|p| (p.to_path_buf(), content))
+            }).collect();
+            return Ok(Rendered { expanded, outputs });
+        }
+    }
+}
+
+// Recoverable errors do not abort the build, they are all reported together at the end.
+fn do_run(y: &mut Yatt) -> Result<Rope, YattError> {
+    match do_build(y) {
+        Err(YattError::Expansion(e)) if !y.state.errors.is_empty() => {
+            // Also report what went wrong before the error that aborted the build.
+            y.state.errors.push(e);
+            return Err(collected_errors(y));
+        }
+        result => return result,
+    }
+}
+
+fn collected_errors(y: &mut Yatt) -> YattError {
+    let mut errors = std::mem::take(&mut y.state.errors);
+    if errors.len() == 1 {
+        return YattError::Expansion(errors.pop().unwrap());
+    } else {
+        return YattError::Expansions(errors);
+    }
+}
+
+// Expand the document in a single pass, render all math that is not cached yet, then resolve all cross references and write the outputs.
+fn do_build(y: &mut Yatt) -> Result<Rope, YattError> {
+    y.configure()?;
+    y.dependencies.insert(y.state.entrypoint.clone());
+
+    match y.state.fs.read_to_string(&y.state.entrypoint) {
+        Err(e) => return Err(YattError::EntryIO(e)),
+        Ok(entry) => {
+            let source_offset = y.source.add_file(&y.state.entrypoint, &entry, Trace(None));
+            deferred::check_source(&entry, source_offset)?;
+            let ast = parse::parse(&entry, y, source_offset)?;
+            let expanded = macros::expand(ast, y)?;
+
+            let cache_path = y.state.base_dir().join(tex::CACHE_FILE);
+            y.state.tex_cache = RefCell::new(TexCache::load(&*y.state.fs, &cache_path));
+            render_math(&mut y.state);
+
+            let mut resolver = Resolver::new(&y.state);
+            resolver.resolve_all(&y.state)?;
+            y.state.errors.append(&mut resolver.errors);
+
+            // Keep what was rendered even if the build fails.
+            let cache = y.state.tex_cache.borrow();
+            if y.state.verbosity >= Verbosity::Verbose {
+                println!("Rendered {} formulas that were not cached.", cache.rendered());
+            }
+            cache.save(&*y.state.fs, &cache_path).map_err(|e| ExpansionError::OutputIO(e, cache_path.clone(), Trace(None)))?;
+            drop(cache);
+
+            if !y.state.errors.is_empty() {
+                return Err(collected_errors(y));
+            }
+
+            y.state.resolve_writes(&mut resolver)?;
+            y.state.warnings = links::check(&y.state, &y.source);
+            if y.validate_html {
+                y.state.warnings.append(&mut validate::check(&y.state));
+            }
+            y.state.flush_writes()?;
+            return Ok(resolver.resolve(&y.state, &expanded.to_string())?.into());
+        }
+    }
+}
+
+// Render all math in a single parallel batch. KaTeX must see the actual cross references, so those get resolved
+// first. Math whose cross references cannot be resolved yet, because they contain math themselves or fail to
+// resolve, is left to the resolver, which renders it on its own and reports the errors.
+fn render_math(state: &mut State) {
+    let mut resolver = Resolver::new(state);
+    let mut batched = Vec::new();
+    let mut jobs = Vec::new();
+    for (i, job) in state.tex_jobs.iter().enumerate() {
+        let errors = resolver.errors.len();
+        if let Ok(content) = resolver.resolve(state, &job.content) {
+            if resolver.errors.len() == errors {
+                batched.push(i);
+                jobs.push(TexJob { content, ..job.clone() });
+            }
+        }
+    }
+
+    let rendered = tex::render_all_cached(&jobs, state.tex_cache.get_mut());
+    state.rendered_tex = vec![None; state.tex_jobs.len()];
+    for (i, rendered) in batched.into_iter().zip(rendered) {
+        state.rendered_tex[i] = Some(rendered);
+    }
+}
+
+struct Yatt {
+    pub state: State,
+    source: SourceMap,
+    macros: MacroRegistry,
+    dependencies: BTreeSet<PathBuf>, // all files the build read from
+    config: ProjectConfig,
+    domain: Option<String>,
+    output_dir: Option<PathBuf>,
+    live_reload: bool,
+    validate_html: bool,
+    verbosity: Verbosity,
+    message_format: MessageFormat,
+}
+
+impl Yatt {
+    fn new(c: RunConfiguration) -> Result<Self, io::Error> {
+        let entrypoint = std::env::current_dir()?.join(c.entrypoint);
+
+        let mut y = Yatt {
+            state: State::new(entrypoint, Rc::from(c.fs)),
+            source: SourceMap::new(),
+            macros: c.macros,
+            dependencies: BTreeSet::new(),
+            config: ProjectConfig::default(),
+            domain: c.domain,
+            output_dir: c.output_dir,
+            live_reload: c.live_reload,
+            validate_html: c.validate_html,
+            verbosity: c.verbosity,
+            message_format: c.message_format,
+        };
+        y.state = y.fresh_state();
+        return Ok(y);
+    }
+
+    // (Re)load the project config and start from a state that uses it.
+    fn configure(&mut self) -> Result<(), YattError> {
+        let path = self.state.base_dir().join(CONFIG_FILE);
+        self.dependencies.insert(path.clone());
+
+        self.config = if self.state.fs.exists(&path) {
+            match self.state.fs.read_to_string(&path) {
+                Err(e) => return Err(YattError::ConfigIO(e, path)),
+                Ok(s) => ProjectConfig::parse(&s).map_err(|e| YattError::Config(e, path))?,
+            }
+        } else {
+            ProjectConfig::default()
+        };
+
+        self.state = self.fresh_state();
+        if self.state.base_dir().starts_with(&self.state.output_dir) {
+            return Err(YattError::OutputDir(self.state.output_dir.clone()));
+        }
+        return Ok(());
+    }
+
+    fn fresh_state(&self) -> State {
+        let mut state = State::new(self.state.entrypoint.clone(), self.state.fs.clone());
+        self.config.apply(&mut state);
+        if let Some(domain) = &self.domain {
+            state.domain = domain.clone();
+        }
+        if let Some(output_dir) = &self.output_dir {
+            state.output_dir = normalize(&state.base_dir().join(output_dir));
+        }
+        state.verbosity = self.verbosity;
+        return state;
+    }
+
+    // Prepare for building again from scratch.
+    fn reset(&mut self) {
+        self.state = self.fresh_state();
+        self.source = SourceMap::new();
+        self.dependencies.clear();
+    }
+}
+
+#[derive(Error, Debug)]
+enum YattError {
+    #[error("never printed")]
+    EntryIO(#[from] io::Error),
+    #[error("never printed")]
+    Parse(#[from] ParseError),
+    #[error("never printed")]
+    Expansion(#[from] ExpansionError),
+    #[error("never printed")]
+    Expansions(Vec<ExpansionError>),
+    #[error("never printed")]
+    ConfigIO(io::Error, PathBuf),
+    #[error("never printed")]
+    Config(toml::de::Error, PathBuf),
+    #[error("never printed")]
+    OutputDir(PathBuf),
+}
+
+pub(crate) fn preview_url(domain: &str, id: &str) -> String {
+    return format!(
+        r###"{}previews/{}.html"###,
+        domain,
+        id,
+    );
+}
+
+// Resolve `.` and `..` components without touching the file system.
+pub(crate) fn normalize(p: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in p.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            c => normalized.push(c),
+        }
+    }
+    return normalized;
+}
+
+impl YattError {
+    // The errors to report, in order.
+    fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            YattError::EntryIO(e) => return vec![Diagnostic::new("EntryIO", "Failed to read entry file:").note(e.to_string())],
+            YattError::Parse(e) => return vec![e.diagnostic()],
+            YattError::Expansion(e) => return vec![e.diagnostic()],
+            YattError::Expansions(errors) => return errors.iter().map(|e| e.diagnostic()).collect(),
+            YattError::ConfigIO(e, path) => {
+                return vec![Diagnostic::new("ConfigIO", format!("Failed to read config file {}:", path.to_string_lossy())).note(e.to_string())];
+            }
+            YattError::Config(e, path) => {
+                return vec![Diagnostic::new("Config", format!("Invalid config file {}:", path.to_string_lossy())).note(e.to_string())];
+            }
+            YattError::OutputDir(dir) => {
+                return vec![Diagnostic::new("OutputDir", format!("The output directory {} contains the entrypoint.", dir.to_string_lossy()))
+                    .note("Builds would write into the sources, and `clean` would delete them. The default is `build` next to the entrypoint.")];
+            }
+        }
+    }
+}
+
+fn print_yatt_error(e: &YattError, source: &SourceMap, format: MessageFormat) {
+    let diagnostics = e.diagnostics();
+
+    match format {
+        MessageFormat::Json => {
+            for d in diagnostics {
+                println!("{}", d.to_json(source));
+            }
+        }
+        MessageFormat::Human => {
+            if diagnostics.len() == 1 {
+                println!("Encountered an error, did not produce new output.");
+                diagnostics[0].print(source);
+            } else {
+                println!("Encountered {} errors, did not produce new output.", diagnostics.len());
+                for d in diagnostics {
+                    println!();
+                    d.print(source);
+                }
+            }
+        }
+    }
+}
+
+pub(crate) struct State {
+    pub entrypoint: PathBuf,
+    pub current_file: PathBuf,
+    pub current_output: PathBuf,
+    pub output_dir: PathBuf,
+    pub sticky_state: StickyState,
+
+    pub domain: String,
+    pub theme: String, // stylesheet of `§template` pages
+
+    pub hsection_level: usize,
+    pub hsection_current_count: [usize; 6],
+    pub hsection_pre_number: [String; 6],
+    pub hsection_post_number: [String; 6],
+    pub hsection_render_number: [bool; 6],
+    pub hsection_name: [String; 6],
+
+    pub aside_current_count: usize,
+    pub aside_level: usize,
+
+    pub box_exercise_current_count: usize,
+    pub box_exercise_level: usize,
+    pub box_other_current_count: usize,
+    pub box_other_level: usize,
+    pub box_current: Option<String>, // id of current box if any
+
+    pub box_previews: HashSet<String>,
+    pub boxless_previews: HashSet<String>,
+
+    pub cases: Vec<usize>,
+
+    pub mathmode: bool,
+    pub fleqn: bool,
+
+    pub venn_id: usize,
+
+    pub user_macros: HashMap<String, UserMacroInfo>,
+    pub user_macro_frames: Vec<UserMacroFrame>, // arguments of the user macros currently being expanded
+
+    pub deferred: Vec<Deferred>,
+    pub pending_writes: Vec<(PathBuf, String, Trace)>,
+    pub copies: Vec<(PathBuf /* from */, PathBuf /* into */)>,
+    pub outputs: BTreeMap<PathBuf, bool>, // every file this build wrote, and whether that changed its content
+    pub errors: Vec<ExpansionError>, // recoverable errors, in the order they occurred
+    pub warnings: Vec<Warning>, // of a successful build
+    pub tex_jobs: Vec<TexJob>, // their content may contain placeholders
+    pub rendered_tex: Vec<Option<Result<String, katex::Error>>>, // filled in after expansion, same order as `tex_jobs`, `None` if left to the resolver
+    pub tex_cache: RefCell<TexCache>,
+    pub link_traces: RefCell<HashMap<String, Vec<Trace>>>, // the references that produced each url, to report broken links there
+    pub references: Vec<Reference>, // every `§cref`, `§r`, `§rtag` and linking math macro, for tooling
+
+    pub fs: Rc<dyn FileSystem>,
+    pub verbosity: Verbosity,
+}
+
+impl State {
+    fn new(entrypoint: PathBuf, fs: Rc<dyn FileSystem>) -> Self {
+        return State {
+            output_dir: normalize(&entrypoint.parent().expect("Entrypoint must not be the root of the file system.").join("build")),
+            current_file: entrypoint.clone(),
+            entrypoint,
+            current_output: "".into(),
+            sticky_state: StickyState::new(),
+
+            domain: "http://localhost:8080/".to_string(),
+            theme: "assets/main.css".to_string(),
+
+            hsection_level: 0,
+            hsection_current_count: [0; 6],
+            hsection_pre_number: ["".into(), "<div>Chapter ".into(), "".into(), "".into(), "".into(), "".into()],
+            hsection_post_number: ["".into(), "</div>".into(), ": ".into(),": ".into(), ": ".into(), ": ".into()],
+            hsection_render_number: [false, true, true, false, false, false],
+            hsection_name: ["".into(), "Chapter".into(), "Section".into(), "Subsection".into(), "Subsubsection".into(), "Subsubsubsection".into()],
+
+            aside_current_count: 0,
+            aside_level: 1,
+
+            box_exercise_current_count: 0,
+            box_exercise_level: 1,
+            box_other_current_count: 0,
+            box_other_level: 1,
+            box_current: None,
+
+            box_previews: HashSet::new(),
+            boxless_previews: HashSet::new(),
+
+            cases: Vec::new(),
+
+            mathmode: false,
+            fleqn: false,
+
+            venn_id: 0,
+
+            user_macros: HashMap::new(),
+            user_macro_frames: Vec::new(),
+
+            deferred: Vec::new(),
+            pending_writes: Vec::new(),
+            copies: Vec::new(),
+            outputs: BTreeMap::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            tex_jobs: Vec::new(),
+            rendered_tex: Vec::new(),
+            tex_cache: RefCell::new(TexCache::default()),
+            link_traces: RefCell::new(HashMap::new()),
+            references: Vec::new(),
+
+            fs,
+            verbosity: Verbosity::Normal,
+        };
+    }
+
+    pub(crate) fn cwd(&self) -> PathBuf {
+        self.current_file.parent().expect("Cwd must not be the root of the file system.").to_path_buf()
+    }
+
+    pub(crate) fn base_dir(&self) -> PathBuf {
+        self.entrypoint.parent().expect("Entrypoint must not be the root of the file system.").to_path_buf()
+    }
+
+    pub(crate) fn current_output_relative(&self, trace: &Trace) -> Result<PathBuf, ExpansionError> {
+        match self.current_output.strip_prefix(&self.output_dir) {
+            Ok(p) => return Ok(p.to_path_buf()),
+            Err(_) => return Err(ExpansionError::NoOutput(trace.clone())),
+        }
+    }
+
+    // The path of an `§output` file, relative output paths mirror the location of the current file inside the base dir.
+    pub(crate) fn resolve_output(&self, path: &Path, trace: &Trace) -> Result<PathBuf, ExpansionError> {
+        let p = if path.is_absolute() {
+            self.output_dir.join(path.strip_prefix("/").unwrap())
+        } else {
+            match self.cwd().strip_prefix(self.base_dir()) {
+                Ok(dir) => self.output_dir.join(dir).join(path),
+                Err(_) => return Err(ExpansionError::OutputOutsideOutputDir(path.to_path_buf(), trace.clone())),
+            }
+        };
+
+        let p = normalize(&p);
+        if p.starts_with(&self.output_dir) && p != self.output_dir {
+            return Ok(p);
+        } else {
+            return Err(ExpansionError::OutputOutsideOutputDir(path.to_path_buf(), trace.clone()));
+        }
+    }
+
+    pub(crate) fn register_id(&mut self, id: impl Into<String>, kind: CrefKind, trace: Trace) -> Result<String, ExpansionError> {
+        let id = id.into();
+        if id == "" {
+            return Ok("".to_string());
+        }
+        let file = self.current_output_relative(&trace)?;
+        let url = format!("{}{}#{}", self.domain, file.to_string_lossy(), id);
+        match self.sticky_state.ids.insert(id, IdInfo {
+            definition: trace.clone(),
+            file,
+            kind,
+        }) {
+            Some(info) => return Err(ExpansionError::DuplicateId(info.definition, trace)),
+            None => return Ok(url),
+        }
+    }
+
+    // Emit a placeholder that gets replaced by the result of `compute` once the whole document has been expanded.
+    pub(crate) fn defer(&mut self, description: impl Into<String>, compute: impl Fn(&State, &mut Resolver) -> Result<String, ExpansionError> + 'static) -> String {
+        self.deferred.push(Deferred {
+            description: description.into(),
+            compute: Box::new(compute),
+        });
+        return placeholder(self.deferred.len() - 1);
+    }
+
+    // Write a file once all placeholders in its content have been resolved.
+    pub(crate) fn write(&mut self, p: PathBuf, content: String, trace: Trace) {
+        self.pending_writes.push((p, content, trace));
+    }
+
+    fn resolve_writes(&mut self, resolver: &mut Resolver) -> Result<(), ExpansionError> {
+        let mut writes = std::mem::take(&mut self.pending_writes);
+        for (_, content, _) in writes.iter_mut() {
+            *content = resolver.resolve(self, content)?;
+        }
+        self.pending_writes = writes;
+        return Ok(());
+    }
+
+    // Files whose content would not change are left untouched, so that their modification times stay the same.
+    fn flush_writes(&mut self) -> Result<(), ExpansionError> {
+        let writes = std::mem::take(&mut self.pending_writes);
+        for (p, content, trace) in writes {
+            let changed = self.fs.read_to_string(&p).map_or(true, |old| old != content);
+
+            if changed {
+                if self.verbosity >= Verbosity::Verbose {
+                    println!("Writing {}", p.to_string_lossy());
+                }
+                self.fs.write(&p, &content).map_err(|e| ExpansionError::OutputIO(e, p.clone(), trace.clone()))?;
+            }
+
+            *self.outputs.entry(p).or_insert(false) |= changed;
+        }
+        return Ok(());
+    }
+
+    // The file that a `§copy` copied to the given path of the output directory.
+    pub(crate) fn copied_source(&self, p: &Path) -> Option<PathBuf> {
+        for (from, into) in self.copies.iter().rev() {
+            if let Ok(rest) = p.strip_prefix(into.join(from.file_name()?)) {
+                return Some(from.join(rest));
+            }
+        }
+        return None;
+    }
+
+    pub(crate) fn create_preview(&mut self, id: impl Into<String>, content: impl Into<String>) -> Result<(), ExpansionError> {
+        let p = self.output_dir.join(format!(r#"previews/{}.html"#, id.into()));
+        self.write(p, content.into(), Trace(None));
+        return Ok(());
+    }
+
+    pub(crate) fn create_box_previews(&mut self, content: impl Into<String>) -> Result<(), ExpansionError> {
+        let content = content.into();
+
+        for id in self.box_previews.clone().iter() {
+            let p = self.output_dir.join(format!(r#"previews/{}.html"#, id));
+            self.write(p, content.clone(), Trace(None));
+        }
+
+        self.box_previews.clear();
+        return Ok(());
+    }
+
+    pub(crate) fn create_boxless_previews(&mut self, content: impl Into<String>) -> Result<(), ExpansionError> {
+        let content = format!(r###"<article>{}</article>"###, content.into());
+
+        for id in self.boxless_previews.clone().iter() {
+            let p = self.output_dir.join(format!(r#"previews/{}.html"#, id));
+            self.write(p, content.clone(), Trace(None));
+        }
+
+        self.boxless_previews.clear();
+        return Ok(());
+    }
+
+    // A placeholder for the url of the given id, under the current domain.
+    pub(crate) fn resolve_id_to_url(&mut self, id: impl Into<String>, trace: Trace) -> String {
+        let id = id.into();
+        let domain = self.domain.clone();
+        return self.defer(format!("reference to id `{}`", id), move |state, _| state.url_of_id(&domain, &id, &trace));
+    }
+
+    pub(crate) fn url_of_id(&self, domain: &str, id: &str, trace: &Trace) -> Result<String, ExpansionError> {
+        match self.sticky_state.ids.get(id) {
+            None => return Err(ExpansionError::UnknownId(trace.clone())),
+            Some(info) => {
+                return Ok(self.traced_url(format!(
+                    "{}{}#{}",
+                    domain,
+                    info.file.to_string_lossy(),
+                    id,
+                ), trace));
+            }
+        }
+    }
+
+    // Remember the reference that produced the url, so that links::check can point at it.
+    pub(crate) fn traced_url(&self, url: String, trace: &Trace) -> String {
+        self.link_traces.borrow_mut().entry(url.clone()).or_default().push(trace.clone());
+        return url;
+    }
+
+    pub(crate) fn id_to_preview_url(&self, id: impl Into<String>) -> String {
+        return preview_url(&self.domain, &id.into());
+    }
+
+    // A placeholder for `link(url, preview_url)`, where the urls are those of the id that the given math id refers to.
+    pub(crate) fn defer_math_link(&mut self, math_id: impl Into<String>, trace: Trace, link: impl Fn(&str, &str) -> String + 'static) -> String {
+        let math_id = math_id.into();
+        let domain = self.domain.clone();
+        self.references.push(Reference { kind: ReferenceKind::MathId, name: math_id.clone(), trace: trace.clone() });
+        return self.defer(format!("math id `{}`", math_id), move |state, _| {
+            match state.sticky_state.math_definitions.get(&math_id) {
+                None => return Err(ExpansionError::UnknownMathId(trace.clone(), math_id.to_string())),
+                Some((id, _)) => return Ok(link(&state.url_of_id(&domain, id, &trace)?, &preview_url(&domain, id))),
+            }
+        });
+    }
+
+    pub(crate) fn resolve_defined_to_preview_url(&self, id: impl Into<String>, trace: Trace) -> Result<String, ExpansionError> {
+        let id = id.into();
+        match self.sticky_state.defined.get(&id) {
+            Some(info) => {
+                return Ok(info.preview.clone());
+            }
+            None => {
+                return Err(ExpansionError::UnknownId(trace));
+            }
+        }
+    }
+
+    pub(crate) fn register_define(&mut self, defined: impl Into<String>, href: String, preview: String, singular: String, plural: String, trace: Trace) -> Result<(), ExpansionError> {
+        let defined = defined.into();
+        if defined == "" {
+            return Err(ExpansionError::EmptyDefine(trace));
+        }
+
+        match self.sticky_state.defined.insert(defined.into(), DefinedInfo {
+            definition: trace.clone(),
+            href,
+            preview,
+            singular,
+            plural,
+        }) {
+            Some(info) => return Err(ExpansionError::DuplicateDefine(info.definition, trace)),
+            None => return Ok(()),
+        }
+    }
+
+    pub(crate) fn resolve_defined_to_url(&self, defined: impl Into<String>, trace: Trace) -> Result<String, ExpansionError> {
+        let defined = defined.into();
+        match self.sticky_state.defined.get(&defined) {
+            None => return Err(ExpansionError::UnknownDefine(trace)),
+            Some(info) => {
+                return Ok(info.href.clone());
+            }
+        }
+    }
+
+    pub(crate) fn claim_name(&self, id: &str, trace: Trace) -> Result<String, ExpansionError> {
+        match self.sticky_state.boxes.get(id) {
+            None => return Err(ExpansionError::UnknownId(trace)),
+            Some(box_info) => {
+                return Ok(format!("{}&nbsp;{}", box_info.name, box_info.numbering,));
+            }
+        }
+    }
+
+    pub(crate) fn enable_mathmode(&mut self, trace: &Trace) -> Result<(), ExpansionError> {
+        if self.mathmode {
+            return Err(ExpansionError::AlreadyMathmode(trace.clone()));
+        } else {
+            self.mathmode = true;
+            return Ok(());
+        }
+    }
+
+    pub(crate) fn disable_mathmode(&mut self, _trace: &Trace) -> Result<(), ExpansionError> {
+        self.mathmode = false;
+        return Ok(());
+    }
+}
+
+#[derive(Clone)]
+pub enum BoxKind {
+    Exercise,
+    Proof,
+    Other(OtherBoxKind),
+}
+
+impl BoxKind {
+    pub(crate) fn class(&self) -> String {
+        match self {
+            BoxKind::Exercise => "exercise".to_string(),
+            BoxKind::Proof => "proof".to_string(),
+            BoxKind::Other(OtherBoxKind::Fact) => "fact".to_string(),
+            BoxKind::Other(OtherBoxKind::Example) => "example".to_string(),
+            BoxKind::Other(OtherBoxKind::Definition) => "definition".to_string(),
+        }
+    }
+
+    pub(crate) fn exercise() -> Self {
+        BoxKind::Exercise
+    }
+
+    pub(crate) fn proof() -> Self {
+        BoxKind::Proof
+    }
+
+    pub(crate) fn fact() -> Self {
+        BoxKind::Other(OtherBoxKind::Fact)
+    }
+
+    pub(crate) fn example() -> Self {
+        BoxKind::Other(OtherBoxKind::Example)
+    }
+
+    pub(crate) fn definition() -> Self {
+        BoxKind::Other(OtherBoxKind::Definition)
+    }
+}
+
+#[derive(Clone)]
+pub enum OtherBoxKind {
+    Fact,
+    Example,
+    Definition,
+}
+
+#[derive(Clone)]
+pub(crate) struct StickyState {
+    pub ids: HashMap<String, IdInfo>,
+    pub hsections: HashMap<String, HSectionInfo>,
+    pub boxes: HashMap<String, BoxInfo>,
+    pub defined: HashMap<String, DefinedInfo>,
+    pub math_definitions: HashMap<String, (String, Trace)>,
+    pub tag_definitions: HashMap<String, (Rope, bool /* r (false) or cref (true)*/, Trace)>,
+    pub cases: HashMap<String, String>,
+    pub hsections_structure: HSections,
+    pub solved: HashSet<String>, // ids of the boxes that contain a `§solution` or `§proof_as_exercise`
+}
+
+impl StickyState {
+    fn new() -> Self {
+        StickyState {
+            ids: HashMap::new(),
+            hsections: HashMap::new(),
+            boxes: HashMap::new(),
+            defined: HashMap::new(),
+            math_definitions: HashMap::new(),
+            tag_definitions: HashMap::new(),
+            cases: HashMap::new(),
+            hsections_structure: HSections::new(),
+            solved: HashSet::new(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum CrefKind {
+    HSection,
+    Box,
+    BoxlessDefinition,
+    Case,
+}
+
+#[derive(Clone)]
+pub(crate) struct IdInfo {
+    pub definition: Trace,
+    pub file: PathBuf,
+    pub kind: CrefKind,
+}
+
+#[derive(Clone)]
+pub struct HSectionInfo {
+    pub name: String, // "Chapter", "Section", etc.
+    pub title: String,
+    pub numbering: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct BoxInfo {
+    pub name: String, // "Theorem", "Lemma", etc.
+    pub numbering: String,
+    pub kind: BoxKind,
+    pub classes: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct DefinedInfo {
+    pub definition: Trace,
+    pub href: String,
+    pub preview: String,
+    pub singular: String,
+    pub plural: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ReferenceKind {
+    Id,     // `§cref`
+    Define, // `§r` and friends
+    Tag,    // `§rtag`
+    MathId, // math macros that link to a definition
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Reference {
+    pub kind: ReferenceKind,
+    pub name: String,
+    pub trace: Trace, // of the referenced name
+}
+
+#[derive(Clone)]
+pub(crate) struct UserMacroInfo {
+    pub definition: Trace,
+    pub arguments: usize,
+    pub body: OutInternal,
+}
+
+pub(crate) struct UserMacroFrame {
+    pub args: Vec<OutInternal>,
+    pub params: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::HtmlProblem;
+    use std::path::Path;
+
+    fn render_ok(source: &str, files: &[(&str, &str)]) -> Rendered {
+        let files = files.iter().map(|(p, c)| (PathBuf::from(p), c.to_string())).collect();
+        match render(source, files, MacroRegistry::new()) {
+            Ok(rendered) => return rendered,
+            Err(e) => {
+                e.print();
+                panic!("render failed");
+            }
+        }
+    }
+
+    // Build `/book/entry.gen` from the given files, printing nothing but errors.
+    fn configuration(fs: &MemoryFs, validate_html: bool) -> RunConfiguration {
+        return RunConfiguration {
+            entrypoint: PathBuf::from("/book/entry.gen"),
+            macros: MacroRegistry::new(),
+            fs: Box::new(fs.clone()),
+            domain: None,
+            output_dir: None,
+            live_reload: false,
+            validate_html,
+            verbosity: Verbosity::Quiet,
+            message_format: MessageFormat::Human,
+        };
+    }
+
+    #[test]
+    fn render_in_memory() {
+        let r = render_ok(r###"§output["a.html"](§span(foo) §input["b.gen"])§copy["assets", "."]"###, &[
+            ("b.gen", "§div(bar)"),
+            ("assets/x.css", "x"),
+            ("other/y.css", "y"),
+        ]);
+
+        assert_eq!(r.outputs.get(Path::new("a.html")).unwrap(), "<span>foo</span> <div>bar</div>");
+        assert_eq!(r.outputs.get(Path::new("assets/x.css")).unwrap(), "x");
+        assert_eq!(r.outputs.len(), 2);
+    }
+
+    #[test]
+    fn render_nested_outputs() {
+        let r = render_ok(r###"§input["chapters/c.gen"]"###, &[
+            ("chapters/c.gen", r###"§output["c.html"](c)§output["/top.html"](top)"###),
+        ]);
+
+        assert_eq!(r.outputs.get(Path::new("chapters/c.html")).unwrap(), "c");
+        assert_eq!(r.outputs.get(Path::new("top.html")).unwrap(), "top");
+        assert!(render(r###"§output["../escape.html"](x)"###, HashMap::new(), MacroRegistry::new()).is_err());
+    }
+
+    #[test]
+    fn render_with_config() {
+        let config = r###"
+domain = "https://example.org/"
+output_dir = "site"
+theme = "assets/dark.css"
+"###;
+        let r = render_ok(r###"§output["a.html"](§template()(§define["x"](X) §r(X)))"###, &[(CONFIG_FILE, config)]);
+
+        let a = r.outputs.get(Path::new("a.html")).unwrap();
+        assert!(a.contains(r###"href="./assets/dark.css""###));
+        assert!(a.contains(r###"href="https://example.org/a.html#x""###));
+
+        let files = [(PathBuf::from(CONFIG_FILE), "unknown_setting = 1".to_string())].into_iter().collect();
+        assert!(render("", files, MacroRegistry::new()).is_err());
+    }
+
+    #[test]
+    fn render_forward_references() {
+        // The define inside the box and the box id are only known after the references to them have been expanded.
+        let source = r###"§output["a.html"](§r(set) §definition["s"](Set)(§p(A §define(set) is. §r(set))))"###;
+
+        let r = render_ok(source, &[]);
+        let a = r.outputs.get(Path::new("a.html")).unwrap();
+        assert_eq!(a.matches(r###"<a class="ref definition" href="http://localhost:8080/a.html#s""###).count(), 2);
+        assert!(!contains_placeholder(a));
+    }
+
+    #[test]
+    fn render_table_of_contents() {
+        let source = r###"§output["a.html"](§toc[2]§hsection["c1"](One)(§minitoc§hsection["s1"](Sub)(§hsection["ss"](Deep)(x))§hsection*["s2"](Extra)(y))§hsection["c2"](Two)(z))"###;
+
+        let r = render_ok(source, &[]);
+        let a = r.outputs.get(Path::new("a.html")).unwrap();
+        let link = |id: &str, number: &str, title: &str| format!(
+            r###"<a href="http://localhost:8080/a.html#{}">{}{}</a>"###,
+            id,
+            if number.is_empty() { String::new() } else { format!(r###"<span class="toc_number">{}</span> "###, number) },
+            title,
+        );
+        let extra = format!(r###"<li class="unnumbered">{}</li>"###, link("s2", "", "Extra"));
+
+        // Two levels for the whole document, and everything below the current hsection for the mini-TOC, where the
+        // level of `ss` does not render numbers.
+        assert!(a.starts_with(&format!(r###"<nav class="toc"><ol><li>{}<ol><li>{}</li>{}</ol></li><li>{}</li></ol></nav>"###,
+            link("c1", "1", "One"), link("s1", "1.1", "Sub"), extra, link("c2", "2", "Two"))));
+        assert!(a.contains(&format!(r###"<nav class="toc"><ol><li>{}<ol><li>{}</li></ol></li>{}</ol></nav>"###,
+            link("s1", "1.1", "Sub"), link("ss", "", "Deep"), extra)));
+    }
+
+    #[test]
+    fn render_cyclic_references() {
+        match render(r###"§output["a.html"](§define["x"](x) §set_tag["x"](§rtag["x"]))"###, HashMap::new(), MacroRegistry::new()) {
+            Err(RenderError { error, .. }) => assert!(matches!(*error, YattError::Expansion(ExpansionError::CyclicReferences(_)))),
+            Ok(_) => panic!("expected the tag to be rejected"),
+        }
+    }
+
+    #[test]
+    fn render_reserved_characters() {
+        // Placeholders are delimited by noncharacters, which sources must not contain.
+        let fs = MemoryFs::new();
+        let _ = fs.write(Path::new("/book/entry.gen"), r###"§output["a.html"](§input["c.gen"])"###);
+        let _ = fs.write(Path::new("/book/c.gen"), "ok\n§pre(quoted \u{FDD0}1\u{FDD1})");
+
+        let mut y = Yatt::new(configuration(&fs, false)).unwrap();
+        match do_run(&mut y) {
+            Err(YattError::Expansion(ExpansionError::ReservedCharacter('\u{FDD0}', Trace(Some((start, _)))))) => {
+                let position = y.source.resolve(start).unwrap();
+                assert_eq!((position.filename, position.line, position.col), ("/book/c.gen", 2, 13));
+            }
+            _ => panic!("expected the noncharacter to be rejected"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn render_math_in_order() {
+        let r = render_ok(r###"§$(x) and §$$(y) and §$(\frac{1}{2})"###, &[]);
+        let jobs: Vec<TexJob> = [("x", false), ("y", true), (r###"\frac{1}{2}"###, false)].iter().map(|(content, display)| TexJob {
+            content: content.to_string(),
+            display: *display,
+            fleqn: false,
+            trust: true,
+        }).collect();
+        let rendered: Vec<String> = tex::render_all(&jobs).into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(r.expanded.to_string(), rendered.join(" and "));
+
+        // Spread over threads, whatever the number of cores, the results keep the order of the jobs.
+        let jobs: Vec<TexJob> = ["a", "b", r###"\frac{1)"###, "c", "d", "e", "f"].iter().map(|content| TexJob {
+            content: content.to_string(),
+            display: false,
+            fleqn: false,
+            trust: true,
+        }).collect();
+        let serial: Vec<Option<String>> = tex::render_on(&jobs, 1).into_iter().map(|r| r.ok()).collect();
+        assert_eq!(tex::render_on(&jobs, 3).into_iter().map(|r| r.ok()).collect::<Vec<_>>(), serial);
+        assert!(serial[2].is_none() && serial.iter().filter(|r| r.is_some()).count() == 6);
+
+        match render(r###"§$(\frac{1)"###, HashMap::new(), MacroRegistry::new()) {
+            Err(RenderError { error, .. }) => assert!(matches!(*error, YattError::Expansion(ExpansionError::TeX(..)))),
+            Ok(_) => panic!("expected invalid TeX to be rejected"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn render_math_from_cache() {
+        let cache = |version: &str| format!(
+            r###"{{"katex_version":"{}","renderings":[{{"tex":"x","display":false,"fleqn":false,"trust":true,"html":"cached"}}]}}"###,
+            version,
+        );
+
+        let r = render_ok("§$(x) §$(y)", &[(tex::CACHE_FILE, &cache(katex::KATEX_VERSION))]);
+        assert!(r.expanded.to_string().starts_with("cached <span class=\"katex\">"));
+
+        let r = render_ok("§$(x)", &[(tex::CACHE_FILE, &cache("0.0.0"))]);
+        assert!(r.expanded.to_string().starts_with("<span class=\"katex\">"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn render_math_with_references_in_batch() {
+        let fs = MemoryFs::new();
+        let _ = fs.write(Path::new("/book/entry.gen"), r###"§output["a.html"](§$(1 §$eq 1) §definition["d"](Equality)(§p(§$(1 §$eq 1))) §set_math_id["eq", "d"])"###);
+
+        let mut y = Yatt::new(configuration(&fs, false)).unwrap();
+        assert!(do_run(&mut y).is_ok());
+        assert!(y.state.rendered_tex.iter().all(|rendered| rendered.is_some()));
+        assert_eq!(y.state.tex_cache.borrow().rendered(), 1);
+        assert!(fs.get(Path::new("/book/build/a.html")).unwrap().contains("http://localhost:8080/a.html#d"));
+    }
+
+    #[test]
+    fn rebuild_skips_unchanged_outputs() {
+        let fs = MemoryFs::new();
+        let entrypoint = PathBuf::from("/book/entry.gen");
+        let _ = fs.write(&entrypoint, r###"§output["a.html"](A)§output["b.html"](B)"###);
+
+        let mut y = Yatt::new(configuration(&fs, false)).unwrap();
+        let changed = |y: &Yatt| y.state.outputs.iter().filter(|(_, changed)| **changed).count();
+
+        assert!(do_run(&mut y).is_ok());
+        assert_eq!(changed(&y), 2);
+
+        y.reset();
+        assert!(do_run(&mut y).is_ok());
+        assert_eq!(changed(&y), 0);
+
+        let _ = fs.write(&entrypoint, r###"§output["a.html"](A)§output["b.html"](BB)"###);
+        y.reset();
+        assert!(do_run(&mut y).is_ok());
+        assert_eq!(changed(&y), 1);
+        assert_eq!(fs.get(Path::new("/book/build/b.html")).unwrap(), "BB");
+    }
+
+    #[test]
+    fn clean_keeps_sources() {
+        let fs = MemoryFs::new();
+        let _ = fs.write(Path::new("/book/entry.gen"), r###"§output["a.html"](§input["/chapters/c.gen"])"###);
+        let _ = fs.write(Path::new("/book/chapters/c.gen"), "c");
+        let _ = fs.write(Path::new("/book/build/old.html"), "old");
+        let files = fs.files();
+
+        for output_dir in [".", "chapters"] {
+            let mut c = configuration(&fs, false);
+            c.output_dir = Some(PathBuf::from(output_dir));
+            assert!(!clean(c));
+            assert_eq!(fs.files(), files);
+        }
+
+        assert!(clean(configuration(&fs, false)));
+        assert!(!fs.exists(Path::new("/book/build")));
+        assert_eq!(fs.files().len(), 2);
+
+        // Builds reject such output directories as well, wherever they come from.
+        let _ = fs.write(Path::new("/book/htmlgen.toml"), r###"output_dir = "..""###);
+        let mut y = Yatt::new(configuration(&fs, false)).unwrap();
+        assert!(matches!(do_run(&mut y), Err(YattError::OutputDir(_))));
+        assert!(!clean(configuration(&fs, false)));
+        assert_eq!(fs.files().len(), 3);
+    }
+
+    #[test]
+    fn check_writes_nothing() {
+        let fs = MemoryFs::new();
+        let _ = fs.write(Path::new("/book/img.png"), "png");
+        let _ = fs.write(Path::new("/book/entry.gen"), r###"§copy["img.png", "assets"]§output["a.html"](
+§definition["d"](§define(set))
+§p(§r(set) and §$(x).)
+)"###);
+        let files = fs.files();
+
+        assert!(check(configuration(&fs, false)));
+        assert_eq!(fs.files(), files);
+
+        let _ = fs.write(Path::new("/book/entry.gen"), r###"§output["a.html"](§cref(nope))"###);
+        assert!(!check(configuration(&fs, false)));
+    }
+
+    #[test]
+    fn broken_links() {
+        let fs = MemoryFs::new();
+        let _ = fs.write(Path::new("/book/img.png"), "png");
+        let _ = fs.write(Path::new("/book/entry.gen"), r###"§copy["img.png", "assets"]§output["a.html"](
+<a href="http://localhost:8080/a.html#x">ok</a><span id='x'></span><script>"<a href=\"http://localhost:8080/c.html\">"</script>
+§link(ok)(http://localhost:8080/assets/img.png) §link(gone)(http://localhost:8080/b.html) §link(nope)(http://localhost:8080/a.html#y)
+§link(elsewhere)(https://example.org/b.html) §link(again)(http://localhost:8080/b.html)
+)"###);
+
+        let mut y = Yatt::new(configuration(&fs, false)).unwrap();
+        assert!(do_run(&mut y).is_ok());
+
+        let links: Vec<(&str, Option<&str>)> = y.state.warnings.iter().map(|w| match w {
+            Warning::BrokenLink(_, output, url, missing_id) => {
+                assert_eq!(output, Path::new("/book/build/a.html"));
+                return (url.as_str(), missing_id.as_deref());
+            }
+            _ => panic!("expected only broken links"),
+        }).collect();
+        assert_eq!(links, vec![("http://localhost:8080/b.html", None), ("http://localhost:8080/a.html#y", Some("y"))]);
+    }
+
+    #[test]
+    fn broken_link_location() {
+        // The id of a dropped box is missing from its output, the link is reported at the `§cref`, even in another file.
+        let fs = MemoryFs::new();
+        let _ = fs.write(Path::new("/book/entry.gen"), r###"§output["a.html"](§drop(§definition["d"](x))§input["c.gen"])"###);
+        let _ = fs.write(Path::new("/book/c.gen"), "See\n  §cref(d).");
+
+        let mut y = Yatt::new(configuration(&fs, false)).unwrap();
+        assert!(do_run(&mut y).is_ok());
+
+        // The header of the box in its preview links there too, and is reported at the box.
+        let in_output: Vec<&Warning> = y.state.warnings.iter().filter(|w| matches!(w, Warning::BrokenLink(_, output, ..) if output == Path::new("/book/build/a.html"))).collect();
+        match &in_output[..] {
+            [Warning::BrokenLink(Trace(Some((start, _))), _, url, Some(missing_id))] => {
+                assert_eq!((url.as_str(), missing_id.as_str()), ("http://localhost:8080/a.html#d", "d"));
+                let position = y.source.resolve(*start).unwrap();
+                assert_eq!((position.filename, position.line, position.col), ("/book/c.gen", 2, 3));
+            }
+            _ => panic!("expected a single broken link with a source location"),
+        }
+    }
+
+    #[test]
+    fn validate_html() {
+        let fs = MemoryFs::new();
+        let _ = fs.write(Path::new("/book/entry.gen"), r###"§output["a.html"](<p><div id="x"></div></p><span id="x"><em></span></em>§symbol0 </b>
+§definition["d"](<i>unclosed)
+)"###);
+
+        let mut y = Yatt::new(configuration(&fs, true)).unwrap();
+        assert!(do_run(&mut y).is_ok());
+
+        let problems: Vec<(&HtmlProblem, usize)> = y.state.warnings.iter().map(|w| match w {
+            Warning::Html(Trace(Some((start, _))), _, _, _, problem) => (problem, y.source.resolve(*start).unwrap().line),
+            _ => panic!("expected only html problems with a source location"),
+        }).collect();
+        // The preview of the box is written first.
+        assert_eq!(problems, vec![
+            (&HtmlProblem::Misnested("i".to_string(), "article".to_string()), 2),
+            (&HtmlProblem::InvalidNesting("div".to_string(), "p".to_string()), 1),
+            (&HtmlProblem::DuplicateId("x".to_string()), 1),
+            (&HtmlProblem::Misnested("em".to_string(), "span".to_string()), 1),
+            (&HtmlProblem::Unopened("em".to_string()), 1),
+            (&HtmlProblem::Unopened("b".to_string()), 1),
+            (&HtmlProblem::Misnested("i".to_string(), "article".to_string()), 2),
+        ]);
+    }
+
+    #[test]
+    fn lint_warnings() {
+        let fs = MemoryFs::new();
+        let _ = fs.write(Path::new("/book/entry.gen"), r###"§output["a.html"](
+§definition["d"](§define(set) and §define(unused))
+§exercise["e"](Prove it.)
+§exercise["f"](Prove it.§solution["f"](See §cref(d), §rtag["set"] and §$(1 §$eq 1).))
+§cref(f)§set_math_id["eq", "d"]§set_math_id["neq", "d"]
+§set_tag["set"](sets)§set_tag["unused"](unused)
+)"###);
+
+        let mut y = Yatt::new(configuration(&fs, false)).unwrap();
+        assert!(do_run(&mut y).is_ok());
+
+        let warnings: Vec<(&str, String)> = lint::unused(&y.state).iter().map(|w| {
+            let d = w.diagnostic();
+            assert!(d.warning);
+            return (d.kind, d.message);
+        }).collect();
+        assert_eq!(warnings, vec![
+            ("UnusedDefine", "`unused` is defined but never referenced with `§r` or `§rs`.".to_string()),
+            ("UntargetedId", "Id `e` is never referenced with `§cref`.".to_string()),
+            ("ExerciseWithoutSolution", "Exercise `e` has no `§solution`.".to_string()),
+            ("UnusedMathId", "Math id `neq` is never used by a math macro.".to_string()),
+            ("UnusedTag", "Tag `unused` is never referenced with `§rtag`.".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn render_collects_recoverable_errors() {
+        let source = r###"§output["a.html"](§cref(nope) §r(nothing) §p(a)(b) fine)"###;
+        match render(source, HashMap::new(), MacroRegistry::new()) {
+            Err(RenderError { error, .. }) => match *error {
+                YattError::Expansions(errors) => {
+                    assert_eq!(errors.len(), 3);
+                    assert!(matches!(errors[0], ExpansionError::ArgumentNumber(2, _)));
+                    assert!(matches!(errors[1], ExpansionError::UnknownId(_)));
+                    assert!(matches!(errors[2], ExpansionError::UnknownDefine(_)));
+                }
+                _ => panic!("expected several errors"),
+            },
+            Ok(r) => panic!("expected the errors to be reported, but got {:?}", r.outputs),
+        }
+    }
+
+    #[test]
+    fn source_positions() {
+        let mut source = SourceMap::new();
+        let entry = source.add_file(Path::new("entry.gen"), "one\ntwo §input[\"ch.gen\"]\n", Trace(None));
+        let input = Trace(Some((entry + 8, entry + 24)));
+        let chapter = source.add_file(Path::new("ch.gen"), "x\n\ty §cref(z)", input.clone());
+
+        let p = source.resolve(entry + 8).unwrap();
+        assert_eq!((p.filename, p.line, p.col, p.line_text), ("entry.gen", 2, 5, r###"two §input["ch.gen"]"###));
+
+        let p = source.resolve(chapter + 5).unwrap();
+        assert_eq!((p.filename, p.line, p.col, p.line_text), ("ch.gen", 2, 4, "\ty §cref(z)"));
+        assert!(source.resolve(chapter + 14).is_some());
+        assert!(source.resolve(chapter + 15).is_none());
+
+        assert!(matches!(source.include_stack(chapter + 5)[..], [Trace(Some((8, 24)))]));
+        assert!(source.include_stack(entry + 1).is_empty());
+    }
+
+    #[test]
+    fn json_diagnostics() {
+        let source = "§output[\"a.html\"](\n  §define[\"x\"](one)\n  §define[\"x\"](two)\n)";
+        match render(source, HashMap::new(), MacroRegistry::new()) {
+            Err(RenderError { error, source }) => {
+                let json: Vec<serde_json::Value> = error.diagnostics().iter().map(|d| d.to_json(&source)).collect();
+                assert_eq!(json.len(), 1);
+                assert_eq!(json[0]["kind"], "DuplicateId");
+                assert_eq!(json[0]["message"], "Cannot define the same id multiple times.");
+                assert_eq!(json[0]["file"], "entry.gen");
+                assert_eq!(json[0]["start"], serde_json::json!({ "line": 2, "column": 3 }));
+                assert_eq!(json[0]["end"], serde_json::json!({ "line": 2, "column": 20 }));
+                assert_eq!(json[0]["spans"][1]["label"], "Second definition");
+                assert_eq!(json[0]["spans"][1]["start"]["line"], 3);
+            }
+            Ok(_) => panic!("expected the duplicate define to be rejected"),
+        }
+    }
+
+    #[test]
+    fn language_server() {
+        let fs = MemoryFs::new();
+        let _ = fs.write(Path::new("/book/entry.gen"), "");
+        let mut server = lsp::Server::new(configuration(&fs, false));
+
+        let text = r###"§output["a.html"](§definition["s"](Set)(§p(A §define(set) is.)) §r(set) §cref(s))"###;
+        let uri = "file:///book/entry.gen";
+        // Positions count utf-16 code units.
+        let position = |s: &str| serde_json::json!({ "line": 0, "character": text[..text.find(s).unwrap() + s.len()].encode_utf16().count() });
+        let request = |id: usize, method: &str, s: &str| serde_json::json!({
+            "jsonrpc": "2.0", "id": id, "method": method,
+            "params": { "textDocument": { "uri": uri }, "position": position(s) },
+        });
+        let open = |text: &str| serde_json::json!({
+            "jsonrpc": "2.0", "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": uri, "text": text } },
+        });
+
+        server.handle(&open(text));
+        server.rebuild();
+        assert!(server.take_messages().is_empty());
+
+        server.handle(&request(1, "textDocument/definition", "§r(s"));
+        server.handle(&request(2, "textDocument/hover", "§r(s"));
+        server.handle(&request(3, "textDocument/completion", "§cref("));
+        server.handle(&request(4, "textDocument/completion", "§de"));
+        let responses = server.take_messages();
+
+        assert_eq!(responses[0]["result"]["range"]["start"], position("§p(A "));
+        assert_eq!(responses[1]["result"]["contents"]["value"], "Definition 1: Set\nA set is.");
+        assert_eq!(responses[2]["result"], serde_json::json!([{ "label": "s", "kind": 18 }]));
+        assert!(responses[3]["result"].as_array().unwrap().iter().any(|item| item["label"] == "definition"));
+        assert!(responses[3]["result"].as_array().unwrap().iter().all(|item| item["label"].as_str().unwrap().starts_with("de")));
+
+        // Diagnostics are published until they are fixed.
+        server.handle(&open(&text.replace("§cref(s)", "§cref(nope)")));
+        server.rebuild();
+        let published = server.take_messages();
+        assert_eq!(published[0]["params"]["uri"], uri);
+        assert_eq!(published[0]["params"]["diagnostics"][0]["code"], "UnknownId");
+
+        server.handle(&open(text));
+        server.rebuild();
+        assert_eq!(server.take_messages()[0]["params"]["diagnostics"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn format_source() {
+        let source = "§exercise[\"e\"](\n      §p(\n   text §# comment   \n  )§p(§$(x)(,) §(\n      <svg>\n        <g/>\n      </svg>\n        )\n §solution(\n§pre(\n  keep\n    this)\n)\n   )\n";
+        let formatted = "§exercise[\"e\"](\n  §p(\n    text §# comment\n  )§p(§$(x)(,) §(\n    <svg>\n      <g/>\n    </svg>\n  )\n  §solution(\n    §pre(\n  keep\n    this)\n  )\n)\n";
+        assert_eq!(fmt::format(source).unwrap(), formatted);
+        assert_eq!(fmt::format(formatted).unwrap(), formatted);
+        assert_eq!(fmt::source(&fmt::parse(source).unwrap()), source);
+
+        assert!(matches!(fmt::format("§p(§em(text)"), Err(ParseError::Unclosed(..))));
+
+        let fs = MemoryFs::new();
+        let _ = fs.write(Path::new("/book/a.gen"), source);
+        let _ = fs.write(Path::new("/book/b.gen"), formatted);
+        let paths = [PathBuf::from("/book/a.gen"), PathBuf::from("/book/b.gen")];
+        assert!(!super::fmt(Box::new(fs.clone()), &paths, true, Verbosity::Quiet));
+        assert_eq!(fs.read_to_string(Path::new("/book/a.gen")).unwrap(), source);
+        assert!(super::fmt(Box::new(fs.clone()), &paths, false, Verbosity::Quiet));
+        assert_eq!(fs.read_to_string(Path::new("/book/a.gen")).unwrap(), formatted);
+        assert!(super::fmt(Box::new(fs.clone()), &paths, true, Verbosity::Quiet));
+    }
+
+    #[test]
+    fn render_unclosed_argument() {
+        let source = "§output[\"a.html\"](\n  §p(text\n  §p(more)\n)\n";
+        match render(source, HashMap::new(), MacroRegistry::new()) {
+            Err(RenderError { error, .. }) => match *error {
+                YattError::Parse(ParseError::Unclosed(open, Some((Trace(Some((close, _))), closed)))) => {
+                    assert_eq!(open.trace().0.unwrap().0, source.find('(').unwrap());
+                    assert_eq!(close, source.rfind(')').unwrap());
+                    assert_eq!(closed.trace().0.unwrap().0, source.find("§p(").unwrap() + "§p".len());
+                }
+                _ => panic!("expected an unclosed argument with a hint"),
+            },
+            Ok(_) => panic!("expected the unclosed argument to be rejected"),
+        }
+
+        // Parentheses in top-level text need not be balanced.
+        render_ok("(top level", &[]);
+    }
+
+    #[test]
+    fn render_user_macros() {
+        let r = render_ok(r###"§defmacro["greet", 1](Hello §arg[0] (§param[0]))§greet["p"](§em(you))"###, &[]);
+        assert_eq!(r.expanded.to_string(), "Hello <em>you</em> (p)");
+
+        let error = |source: &str| match render(source, HashMap::new(), MacroRegistry::new()) {
+            Err(RenderError { error, .. }) => return *error,
+            Ok(_) => panic!("expected `{}` to be rejected", source),
+        };
+        assert!(matches!(error(r###"§defmacro["m", 0](a)§defmacro["m", 0](b)"###), YattError::Expansion(ExpansionError::DuplicateMacro(..))));
+        assert!(matches!(error(r###"§arg[0]"###), YattError::Expansion(ExpansionError::ArgumentOutsideMacro(_))));
+        assert!(matches!(error(r###"§defmacro["m", 0](§param[1])§m["x"]"###), YattError::Expansion(ExpansionError::ParameterIndex(1, _))));
+        assert!(matches!(error(r###"§defmacro["em", 1](§arg[0])"###), YattError::Expansion(ExpansionError::RegisteredMacro(_, _))));
+    }
+
+    #[test]
+    fn render_registered_macros() {
+        let mut macros = MacroRegistry::new();
+        macros.register("$qed", |t, p, a| OutInternal::Const(t, p, a, r###"\blacksquare"###));
+        let r = render("§$qed", HashMap::new(), macros).ok().unwrap();
+        assert_eq!(r.expanded.to_string(), r###"\blacksquare"###);
+    }
+}
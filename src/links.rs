@@ -0,0 +1,120 @@
+// Every link into the domain of the site should point at a file that the build produces, and, if it has a fragment,
+// at an element of that file with the fragment as its id. Links are checked once all cross references have been
+// resolved, a broken one is only a warning, since linking to the preview of a `§drop`ped box is fine, for example.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::html::{self, Tag};
+use crate::lint::Warning;
+use crate::macros::Trace;
+use crate::serve::percent_decode;
+use crate::source::SourceMap;
+use crate::{normalize, State};
+
+const LINK_ATTRIBUTES: [&str; 2] = ["href", "data-preview"];
+
+/// All broken links among the pending writes, at most one per output file and url.
+pub(crate) fn check(state: &State, source: &SourceMap) -> Vec<Warning> {
+    // Without a domain, links into the site cannot be told apart from relative ones.
+    if state.domain.is_empty() {
+        return vec![];
+    }
+
+    let outputs: HashMap<&Path, &str> = state.pending_writes.iter().map(|(p, content, _)| (p.as_path(), content.as_str())).collect();
+    let mut ids: HashMap<PathBuf, Option<HashSet<String>>> = HashMap::new();
+    let mut warnings = vec![];
+
+    for (p, content, trace) in &state.pending_writes {
+        if !is_html(p) {
+            continue;
+        }
+
+        let mut reported: HashSet<String> = HashSet::new();
+        for tag in html::tags(content) {
+            for url in LINK_ATTRIBUTES.iter().filter_map(|attribute| tag.attribute(attribute)) {
+                let within = match url.strip_prefix(&state.domain) {
+                    Some(within) => within,
+                    None => continue,
+                };
+                if reported.contains(url) {
+                    continue;
+                }
+
+                let (path, fragment) = match within.split_once('#') {
+                    Some((path, fragment)) => (path, Some(percent_decode(fragment))),
+                    None => (within, None),
+                };
+                let path = path.split('?').next().unwrap_or("");
+                let path = if path.is_empty() || path.ends_with('/') { format!("{}index.html", path) } else { path.to_string() };
+                let target = normalize(&state.output_dir.join(percent_decode(&path)));
+
+                let exists = target.starts_with(&state.output_dir) && (outputs.contains_key(target.as_path()) || match state.copied_source(&target) {
+                    Some(source) => state.fs.exists(&source),
+                    None => false,
+                });
+
+                let missing = if !exists {
+                    Some(None)
+                } else {
+                    match fragment {
+                        Some(fragment) if !fragment.is_empty() => {
+                            let target_ids = ids.entry(target.clone()).or_insert_with(|| ids_of(state, &outputs, &target));
+                            match target_ids {
+                                Some(target_ids) if !target_ids.contains(&fragment) => Some(Some(fragment)),
+                                _ => None,
+                            }
+                        }
+                        _ => None,
+                    }
+                };
+
+                if let Some(fragment) = missing {
+                    reported.insert(url.to_string());
+                    warnings.push(Warning::BrokenLink(link_trace(state, source, p, trace, url), p.clone(), url.to_string(), fragment));
+                }
+            }
+        }
+    }
+
+    return warnings;
+}
+
+pub(crate) fn is_html(p: &Path) -> bool {
+    return matches!(p.extension().and_then(|e| e.to_str()), Some("html") | Some("htm"));
+}
+
+// The ids of all elements of an html file of the build, `None` if the file is not html or cannot be read.
+fn ids_of(state: &State, outputs: &HashMap<&Path, &str>, p: &Path) -> Option<HashSet<String>> {
+    if !is_html(p) {
+        return None;
+    }
+
+    let content = match outputs.get(p) {
+        Some(content) => content.to_string(),
+        None => state.fs.read_to_string(&state.copied_source(p)?).ok()?,
+    };
+    return Some(html::tags(&content).iter().filter_map(|tag: &Tag| tag.attribute("id")).map(|id| id.to_string()).collect());
+}
+
+// The reference within the output that produced the url, links written as raw html are reported at the output itself.
+fn link_trace(state: &State, source: &SourceMap, p: &Path, trace: &Trace, url: &str) -> Trace {
+    let output = output_trace(state, p, trace);
+    if let Some(traces) = state.link_traces.borrow().get(url) {
+        if let Some(t) = traces.iter().find(|t| source.contains(&output, t)) {
+            return t.clone();
+        }
+    }
+    return output;
+}
+
+// Previews are written without a trace, but belong to the id they preview.
+pub(crate) fn output_trace(state: &State, p: &Path, trace: &Trace) -> Trace {
+    if let Trace(None) = trace {
+        let id = p.strip_prefix(state.output_dir.join("previews")).ok().and_then(|id| id.file_stem()).and_then(|id| id.to_str());
+        if let Some(info) = id.and_then(|id| state.sticky_state.ids.get(id)) {
+            return info.definition.clone();
+        }
+    }
+    return trace.clone();
+}
@@ -0,0 +1,198 @@
+// Warnings about content that builds fine but is never used, links nowhere, or is malformed html. Unlike
+// `ExpansionError`s they never fail a build. Broken links are found by every build, malformed html by builds that
+// ask for it, and the rest only by `lint`, computed afterwards from what the `StickyState` and the recorded
+// references say about the whole book.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use crate::diagnostic::Diagnostic;
+use crate::macros::Trace;
+use crate::source::SourceMap;
+use crate::validate::HtmlProblem;
+use crate::vfs::DryRunFs;
+use crate::{do_run, print_yatt_error, BoxKind, CrefKind, MessageFormat, ReferenceKind, RunConfiguration, State, Verbosity, Yatt};
+
+#[derive(Debug)]
+pub(crate) enum Warning {
+    UnusedDefine(Trace, String),
+    UntargetedId(Trace, String),
+    ExerciseWithoutSolution(Trace, String),
+    UnusedMathId(Trace, String),
+    UnusedTag(Trace, String),
+    BrokenLink(Trace, PathBuf /* output */, String /* url */, Option<String> /* missing id, or `None` if the file is missing */),
+    Html(Trace, PathBuf /* output */, usize /* line */, usize /* column */, HtmlProblem),
+}
+
+impl Warning {
+    fn trace(&self) -> &Trace {
+        match self {
+            Warning::UnusedDefine(t, _)
+            | Warning::UntargetedId(t, _)
+            | Warning::ExerciseWithoutSolution(t, _)
+            | Warning::UnusedMathId(t, _)
+            | Warning::UnusedTag(t, _)
+            | Warning::BrokenLink(t, ..)
+            | Warning::Html(t, ..) => return t,
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        match self {
+            Warning::UnusedDefine(t, name) => {
+                return Diagnostic::new("UnusedDefine", format!("`{}` is defined but never referenced with `§r` or `§rs`.", name)).warning().at(t.clone());
+            }
+            Warning::UntargetedId(t, id) => {
+                return Diagnostic::new("UntargetedId", format!("Id `{}` is never referenced with `§cref`.", id)).warning().at(t.clone());
+            }
+            Warning::ExerciseWithoutSolution(t, id) => {
+                return Diagnostic::new("ExerciseWithoutSolution", format!("Exercise `{}` has no `§solution`.", id)).warning().at(t.clone());
+            }
+            Warning::UnusedMathId(t, id) => {
+                return Diagnostic::new("UnusedMathId", format!("Math id `{}` is never used by a math macro.", id)).warning().at(t.clone());
+            }
+            Warning::UnusedTag(t, id) => {
+                return Diagnostic::new("UnusedTag", format!("Tag `{}` is never referenced with `§rtag`.", id)).warning().at(t.clone());
+            }
+            Warning::BrokenLink(t, output, url, missing_id) => {
+                let message = match missing_id {
+                    None => format!("Link to a file that the build does not produce: {}", url),
+                    Some(id) => format!("Link to an element that does not exist, its file has no id `{}`: {}", id, url),
+                };
+                return Diagnostic::new("BrokenLink", message).warning().note(format!("In output file {}", output.to_string_lossy())).at(t.clone());
+            }
+            Warning::Html(t, output, line, col, problem) => {
+                return Diagnostic::new(problem.kind(), problem.message()).warning().note(format!("In output file {}:{}:{}", output.to_string_lossy(), line, col)).at(t.clone());
+            }
+        }
+    }
+}
+
+/// Build without writing any files, then warn about broken links, about defines, ids, math ids and tags that are
+/// never referenced, and about exercises without a solution. Returns whether the build succeeded without warnings.
+pub fn lint(mut c: RunConfiguration) -> bool {
+    c.fs = Box::new(DryRunFs::new(c.fs));
+    let mut y = Yatt::new(c).unwrap();
+
+    if let Err(e) = do_run(&mut y) {
+        print_yatt_error(&e, &y.source, y.message_format);
+        return false;
+    }
+
+    let mut warnings = std::mem::take(&mut y.state.warnings);
+    warnings.append(&mut unused(&y.state));
+    print_warnings(&warnings, &y.source, y.message_format);
+    if warnings.is_empty() && y.verbosity >= Verbosity::Normal {
+        println!("No warnings.");
+    }
+
+    return warnings.is_empty();
+}
+
+pub(crate) fn print_warnings(warnings: &[Warning], source: &SourceMap, format: MessageFormat) {
+    match format {
+        MessageFormat::Json => {
+            for w in warnings {
+                println!("{}", w.diagnostic().to_json(source));
+            }
+        }
+        MessageFormat::Human => {
+            if !warnings.is_empty() {
+                println!("Found {} warning{}.", warnings.len(), if warnings.len() == 1 { "" } else { "s" });
+                for w in warnings {
+                    println!();
+                    w.diagnostic().print(source);
+                }
+            }
+        }
+    }
+}
+
+// The warnings about unused content of a successful build, in source order.
+pub(crate) fn unused(state: &State) -> Vec<Warning> {
+    let sticky = &state.sticky_state;
+
+    // What the references of the build end up pointing at. A `§set_tag` is a define or an id itself, depending on
+    // whether it is referenced like `§r` or like `§cref`, and every referenced define links to its box or `dfn`.
+    let mut referenced_defines = HashSet::new();
+    let mut targeted_ids = HashSet::new();
+    let mut used_math_ids = HashSet::new();
+    let mut referenced_tags = HashSet::new();
+    for r in &state.references {
+        match r.kind {
+            ReferenceKind::Id => {
+                targeted_ids.insert(r.name.as_str());
+            }
+            ReferenceKind::Define => {
+                referenced_defines.insert(r.name.as_str());
+            }
+            ReferenceKind::MathId => {
+                used_math_ids.insert(r.name.as_str());
+                if let Some((id, _)) = sticky.math_definitions.get(&r.name) {
+                    targeted_ids.insert(id.as_str());
+                }
+            }
+            ReferenceKind::Tag => {
+                referenced_tags.insert(r.name.as_str());
+                match sticky.tag_definitions.get(&r.name) {
+                    Some((_, true, _)) => {
+                        targeted_ids.insert(r.name.as_str());
+                    }
+                    _ => {
+                        referenced_defines.insert(r.name.as_str());
+                    }
+                }
+            }
+        }
+    }
+    for name in &referenced_defines {
+        if let Some((_, id)) = sticky.defined.get(*name).and_then(|info| info.href.rsplit_once('#')) {
+            targeted_ids.insert(id);
+        }
+    }
+
+    let mut warnings = vec![];
+
+    for (name, info) in &sticky.defined {
+        if !referenced_defines.contains(name.as_str()) {
+            warnings.push(Warning::UnusedDefine(info.definition.clone(), name.clone()));
+        }
+    }
+
+    for (id, info) in &sticky.ids {
+        // Boxless definitions are covered by their defines, proofs are reached through what they prove, and
+        // hsections through the navigation.
+        let box_kind = sticky.boxes.get(id).map(|b| &b.kind);
+        let lintable = match info.kind {
+            CrefKind::Box => !matches!(box_kind, Some(BoxKind::Proof)),
+            CrefKind::Case => true,
+            CrefKind::HSection | CrefKind::BoxlessDefinition => false,
+        };
+        if lintable && !targeted_ids.contains(id.as_str()) {
+            warnings.push(Warning::UntargetedId(info.definition.clone(), id.clone()));
+        }
+
+        if matches!(box_kind, Some(BoxKind::Exercise)) && !sticky.solved.contains(id) {
+            warnings.push(Warning::ExerciseWithoutSolution(info.definition.clone(), id.clone()));
+        }
+    }
+
+    for (id, (_, t)) in &sticky.math_definitions {
+        if !used_math_ids.contains(id.as_str()) {
+            warnings.push(Warning::UnusedMathId(t.clone(), id.clone()));
+        }
+    }
+
+    for (id, (_, _, t)) in &sticky.tag_definitions {
+        if !referenced_tags.contains(id.as_str()) {
+            warnings.push(Warning::UnusedTag(t.clone(), id.clone()));
+        }
+    }
+
+    // Warnings without a source location go last.
+    warnings.sort_by_key(|w| match w.trace() {
+        Trace(Some((start, end))) => (false, *start, *end),
+        Trace(None) => (true, 0, 0),
+    });
+    return warnings;
+}
@@ -0,0 +1,544 @@
+// A language server for .gen files, speaking JSON-RPC over stdio.
+//
+// Every change rebuilds the whole project, reading open documents from the editor instead of the disk and
+// keeping all output in memory. Go-to-definition and hover then look up the references that expansion recorded,
+// completion only looks at the text before the cursor, since what is being typed rarely parses.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::diagnostic::Diagnostic;
+use crate::macros::Trace;
+use crate::serve::percent_decode;
+use crate::source::{Position, SourceMap};
+use crate::vfs::{FileSystem, MemoryFs};
+use crate::{do_run, normalize, Reference, ReferenceKind, RunConfiguration, Verbosity, Yatt, YattError};
+
+// The macros whose first argument names a define.
+const DEFINE_REFERENCES: [&str; 8] = ["r", "R", "rs", "Rs", "rdef", "Rdef", "rsdef", "Rsdef"];
+
+/// Run a language server until the editor asks it to exit, returns whether it was shut down properly.
+pub fn lsp(c: RunConfiguration) -> bool {
+    let mut server = Server::new(c);
+
+    // Read on another thread, so that all changes which arrive during a build are handled by a single rebuild.
+    let (sender, incoming) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = BufReader::new(io::stdin());
+        while let Ok(Some(message)) = read_message(&mut stdin) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut stdout = io::stdout();
+    while let Ok(message) = incoming.recv() {
+        let mut next = Some(message);
+        while let Some(message) = next {
+            server.handle(&message);
+            server.send_all(&mut stdout);
+            if let Some(success) = server.exit {
+                return success;
+            }
+            next = incoming.try_recv().ok();
+        }
+
+        if server.dirty {
+            server.rebuild();
+            server.send_all(&mut stdout);
+        }
+    }
+
+    return false;
+}
+
+fn read_message(r: &mut impl BufRead) -> Result<Option<Value>, io::Error> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or(0)];
+    r.read_exact(&mut body)?;
+    return Ok(Some(serde_json::from_slice(&body).unwrap_or(Value::Null)));
+}
+
+fn write_message(w: &mut impl Write, message: &Value) -> Result<(), io::Error> {
+    let body = message.to_string();
+    write!(w, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    return w.flush();
+}
+
+// Open documents take precedence over the disk, writes only go to memory.
+struct EditorFs {
+    documents: Rc<RefCell<HashMap<PathBuf, String>>>,
+    written: MemoryFs,
+    disk: Box<dyn FileSystem>,
+}
+
+impl FileSystem for EditorFs {
+    fn read_to_string(&self, p: &Path) -> Result<String, io::Error> {
+        if let Some(document) = self.documents.borrow().get(&normalize(p)) {
+            return Ok(document.clone());
+        }
+        match self.written.get(p) {
+            Some(content) => return Ok(content),
+            None => return self.disk.read_to_string(p),
+        }
+    }
+
+    fn write(&self, p: &Path, content: &str) -> Result<(), io::Error> {
+        return self.written.write(p, content);
+    }
+
+    fn copy_into(&self, from: &Path, _to: &Path) -> Result<(), fs_extra::error::Error> {
+        if self.exists(from) {
+            return Ok(());
+        } else {
+            return Err(fs_extra::error::Error::new(fs_extra::error::ErrorKind::NotFound, "source does not exist"));
+        }
+    }
+
+    fn exists(&self, p: &Path) -> bool {
+        return self.documents.borrow().contains_key(&normalize(p)) || self.written.exists(p) || self.disk.exists(p);
+    }
+
+    fn remove_dir_all(&self, p: &Path) -> Result<(), io::Error> {
+        return self.written.remove_dir_all(p);
+    }
+}
+
+// What completion offers, as of the last build that got past parsing.
+#[derive(Default)]
+struct Completions {
+    macros: Vec<String>,
+    ids: Vec<String>,
+    defines: Vec<String>,
+    tags: Vec<String>,
+}
+
+impl Completions {
+    fn new(y: &Yatt) -> Self {
+        fn sorted<'a>(names: impl Iterator<Item = &'a str>) -> Vec<String> {
+            let names: BTreeSet<&str> = names.collect();
+            return names.into_iter().map(|name| name.to_string()).collect();
+        }
+
+        let sticky = &y.state.sticky_state;
+        return Completions {
+            macros: sorted(y.macros.names().chain(y.state.user_macros.keys().map(|name| name.as_str()))),
+            ids: sorted(sticky.ids.keys().map(|id| id.as_str())),
+            defines: sorted(sticky.defined.keys().map(|name| name.as_str())),
+            tags: sorted(sticky.tag_definitions.keys().map(|id| id.as_str())),
+        };
+    }
+}
+
+pub(crate) struct Server {
+    y: Yatt,
+    documents: Rc<RefCell<HashMap<PathBuf, String>>>,
+    completions: Completions,
+    published: BTreeSet<PathBuf>, // files whose diagnostics the editor currently shows
+    dirty: bool,                  // whether documents changed since the last build
+    shutdown: bool,
+    exit: Option<bool>,
+    outbox: Vec<Value>,
+}
+
+impl Server {
+    pub fn new(mut c: RunConfiguration) -> Self {
+        let documents = Rc::new(RefCell::new(HashMap::new()));
+        c.fs = Box::new(EditorFs {
+            documents: documents.clone(),
+            written: MemoryFs::new(),
+            disk: c.fs,
+        });
+        // Anything but messages on stdout would confuse the editor.
+        c.verbosity = Verbosity::Quiet;
+
+        return Server {
+            y: Yatt::new(c).unwrap(),
+            documents,
+            completions: Completions::default(),
+            published: BTreeSet::new(),
+            dirty: false,
+            shutdown: false,
+            exit: None,
+            outbox: Vec::new(),
+        };
+    }
+
+    fn send_all(&mut self, w: &mut impl Write) {
+        for message in self.take_messages() {
+            let _ = write_message(w, &message);
+        }
+    }
+
+    /// The responses and notifications to send, in order.
+    pub fn take_messages(&mut self) -> Vec<Value> {
+        return std::mem::take(&mut self.outbox);
+    }
+
+    fn respond(&mut self, id: &Value, result: Value) {
+        self.outbox.push(json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.outbox.push(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    pub fn handle(&mut self, message: &Value) {
+        let params = &message["params"];
+        let id = match message.get("id") {
+            Some(id) => id,
+            None => &Value::Null,
+        };
+
+        match message["method"].as_str() {
+            Some("initialize") => {
+                self.respond(id, json!({
+                    "capabilities": {
+                        "textDocumentSync": 1, // always send the full text
+                        "completionProvider": { "triggerCharacters": ["§", "(", "\""] },
+                        "definitionProvider": true,
+                        "hoverProvider": true,
+                    },
+                    "serverInfo": { "name": "atm_htmlgen" },
+                }));
+            }
+            Some("initialized") => self.dirty = true,
+            Some("shutdown") => {
+                self.shutdown = true;
+                self.respond(id, Value::Null);
+            }
+            Some("exit") => self.exit = Some(self.shutdown),
+
+            Some("textDocument/didOpen") => {
+                if let Some(path) = document_path(params) {
+                    let text = params["textDocument"]["text"].as_str().unwrap_or("").to_string();
+                    self.documents.borrow_mut().insert(path, text);
+                    self.dirty = true;
+                }
+            }
+            Some("textDocument/didChange") => {
+                let text = params["contentChanges"].as_array().and_then(|changes| changes.last()).and_then(|change| change["text"].as_str());
+                if let (Some(path), Some(text)) = (document_path(params), text) {
+                    self.documents.borrow_mut().insert(path, text.to_string());
+                    self.dirty = true;
+                }
+            }
+            Some("textDocument/didClose") => {
+                if let Some(path) = document_path(params) {
+                    self.documents.borrow_mut().remove(&path);
+                    self.dirty = true;
+                }
+            }
+            Some("textDocument/didSave") => self.dirty = true,
+
+            Some("textDocument/completion") => {
+                let items = self.completion(params).unwrap_or_default();
+                self.respond(id, json!(items));
+            }
+            Some("textDocument/definition") => {
+                if self.dirty {
+                    self.rebuild();
+                }
+                let location = self.definition(params).unwrap_or(Value::Null);
+                self.respond(id, location);
+            }
+            Some("textDocument/hover") => {
+                if self.dirty {
+                    self.rebuild();
+                }
+                let hover = self.hover(params).unwrap_or(Value::Null);
+                self.respond(id, hover);
+            }
+
+            Some(method) if !id.is_null() => {
+                self.outbox.push(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": -32601, "message": format!("Unsupported method {}.", method) },
+                }));
+            }
+            // Notifications we do not care about, and responses to requests we never send.
+            _ => {}
+        }
+    }
+
+    pub fn rebuild(&mut self) {
+        self.dirty = false;
+        self.y.reset();
+
+        let diagnostics = match do_run(&mut self.y) {
+            Ok(_) => {
+                self.completions = Completions::new(&self.y);
+                Vec::new()
+            }
+            Err(e) => {
+                if !matches!(e, YattError::Parse(_)) {
+                    self.completions = Completions::new(&self.y);
+                }
+                e.diagnostics()
+            }
+        };
+
+        self.publish(diagnostics);
+    }
+
+    fn publish(&mut self, diagnostics: Vec<Diagnostic>) {
+        let source = &self.y.source;
+        let entrypoint = normalize(&self.y.state.entrypoint);
+
+        let mut by_file: BTreeMap<PathBuf, Vec<Value>> = BTreeMap::new();
+        for d in diagnostics {
+            // Errors without a position, like an unreadable config, are shown at the start of the entrypoint.
+            let (uri, range) = match d.spans.first().and_then(|(_, t)| location(source, t)) {
+                Some(location) => (location["uri"].clone(), location["range"].clone()),
+                None => (json!(path_to_uri(&entrypoint)), json!({ "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 0 } })),
+            };
+
+            let related: Vec<Value> = d.spans.iter().skip(1).filter_map(|(label, t)| {
+                let location = location(source, t)?;
+                return Some(json!({ "location": location, "message": label.clone().unwrap_or_default() }));
+            }).collect();
+
+            let mut message = d.message.trim().to_string();
+            for note in &d.notes {
+                message.push('\n');
+                message.push_str(note.trim());
+            }
+
+            by_file.entry(uri_to_path(uri.as_str().unwrap_or("")).unwrap_or_default()).or_default().push(json!({
+                "range": range,
+                "severity": 1,
+                "source": "atm_htmlgen",
+                "code": d.kind,
+                "message": message,
+                "relatedInformation": related,
+            }));
+        }
+
+        // Clear what was fixed.
+        for path in std::mem::take(&mut self.published) {
+            by_file.entry(path).or_default();
+        }
+
+        for (path, diagnostics) in by_file {
+            if !diagnostics.is_empty() {
+                self.published.insert(path.clone());
+            }
+            self.notify("textDocument/publishDiagnostics", json!({ "uri": path_to_uri(&path), "diagnostics": diagnostics }));
+        }
+    }
+
+    fn completion(&self, params: &Value) -> Option<Vec<Value>> {
+        let path = document_path(params)?;
+        let text = self.y.state.fs.read_to_string(&path).ok()?;
+        let offset = byte_offset(&text, &params["position"])?;
+        let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let prefix = &text[line_start..offset];
+
+        let after_macro = &prefix[prefix.rfind('§')? + '§'.len_utf8()..];
+        let (names, kind, partial) = if let Some((name, partial)) = after_macro.split_once('(') {
+            if partial.contains(['(', ')']) {
+                return None;
+            } else if name == "cref" {
+                (&self.completions.ids, 18 /* reference */, partial)
+            } else if DEFINE_REFERENCES.contains(&name) {
+                (&self.completions.defines, 18, partial)
+            } else {
+                return None;
+            }
+        } else if let Some(partial) = after_macro.strip_prefix("rtag[") {
+            if partial.contains(']') {
+                return None;
+            }
+            (&self.completions.tags, 18, partial.trim_start_matches('"'))
+        } else if after_macro.contains([' ', '\t', '[', ']', '{', '}', ')']) {
+            return None;
+        } else {
+            (&self.completions.macros, 3 /* function */, after_macro)
+        };
+
+        return Some(names.iter().filter(|name| name.starts_with(partial)).map(|name| json!({ "label": name, "kind": kind })).collect());
+    }
+
+    // The innermost reference at the position of the request.
+    fn reference_at(&self, params: &Value) -> Option<&Reference> {
+        let path = document_path(params)?;
+        let (start, text) = self.y.source.file(&path)?;
+        let offset = start + byte_offset(text, &params["position"])?;
+
+        return self.y.state.references.iter().filter_map(|r| match r.trace {
+            Trace(Some((s, e))) if s <= offset && offset <= e => Some((e - s, r)),
+            _ => None,
+        }).min_by_key(|(len, _)| *len).map(|(_, r)| r);
+    }
+
+    // Where the target of a reference is defined, and the file of its preview.
+    fn target(&self, r: &Reference) -> Option<(Trace, PathBuf)> {
+        let state = &self.y.state;
+        let (kind, name) = match r.kind {
+            ReferenceKind::Tag => match state.sticky_state.tag_definitions.get(&r.name)? {
+                (_, true, _) => (ReferenceKind::Id, &r.name),
+                (_, false, _) => (ReferenceKind::Define, &r.name),
+            },
+            // Math macros link to the id that their math id was set to.
+            ReferenceKind::MathId => (ReferenceKind::Id, &state.sticky_state.math_definitions.get(&r.name)?.0),
+            kind => (kind, &r.name),
+        };
+
+        match kind {
+            ReferenceKind::Define => {
+                let info = state.sticky_state.defined.get(name)?;
+                let preview = state.output_dir.join(info.preview.strip_prefix(&state.domain)?);
+                return Some((info.definition.clone(), preview));
+            }
+            _ => {
+                let info = state.sticky_state.ids.get(name)?;
+                let preview = state.output_dir.join(format!("previews/{}.html", name));
+                return Some((info.definition.clone(), preview));
+            }
+        }
+    }
+
+    fn definition(&self, params: &Value) -> Option<Value> {
+        let (definition, _) = self.target(self.reference_at(params)?)?;
+        return location(&self.y.source, &definition);
+    }
+
+    fn hover(&self, params: &Value) -> Option<Value> {
+        let r = self.reference_at(params)?;
+        let (_, preview) = self.target(r)?;
+        let html = self.y.state.fs.read_to_string(&preview).ok()?;
+
+        return Some(json!({
+            "contents": { "kind": "plaintext", "value": preview_text(&html) },
+            "range": location(&self.y.source, &r.trace)?["range"],
+        }));
+    }
+}
+
+fn document_path(params: &Value) -> Option<PathBuf> {
+    return uri_to_path(params["textDocument"]["uri"].as_str()?);
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    return Some(normalize(Path::new(&percent_decode(uri.strip_prefix("file://")?))));
+}
+
+fn path_to_uri(p: &Path) -> String {
+    let mut uri = "file://".to_string();
+    for byte in p.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    return uri;
+}
+
+// The byte offset of an lsp position, whose characters are counted in utf-16 code units.
+fn byte_offset(text: &str, position: &Value) -> Option<usize> {
+    let line = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+
+    let line_start = if line == 0 { 0 } else { text.match_indices('\n').nth(line - 1)?.0 + 1 };
+    let line_text = text[line_start..].split('\n').next().unwrap_or("");
+
+    let mut units = 0;
+    for (i, c) in line_text.char_indices() {
+        if units >= character {
+            return Some(line_start + i);
+        }
+        units += c.len_utf16();
+    }
+    return Some(line_start + line_text.len());
+}
+
+fn lsp_position(p: &Position) -> Value {
+    let character: usize = p.line_text.chars().take(p.col - 1).map(char::len_utf16).sum();
+    return json!({ "line": p.line - 1, "character": character });
+}
+
+fn location(source: &SourceMap, t: &Trace) -> Option<Value> {
+    let (start, end) = t.0?;
+    let path = source.path_at(start)?;
+    let (start, end) = source.resolve_span(start, end)?;
+    return Some(json!({
+        "uri": path_to_uri(path),
+        "range": { "start": lsp_position(&start), "end": lsp_position(&end) },
+    }));
+}
+
+// The text of a preview, with math reduced to its TeX source.
+pub(crate) fn preview_text(html: &str) -> String {
+    const TEX_START: &str = r#"<annotation encoding="application/x-tex">"#;
+
+    let mut text = String::new();
+    let mut hidden_spans = 0; // KaTeX repeats all math as styled spans, skip those
+    let mut rest = html;
+
+    while let Some(open) = rest.find('<') {
+        if hidden_spans == 0 {
+            text.push_str(&rest[..open]);
+        }
+        let close = match rest[open..].find('>') {
+            Some(i) => open + i,
+            None => break,
+        };
+        let tag = &rest[open + 1..close];
+        rest = &rest[close + 1..];
+
+        if tag.starts_with("math") {
+            let end = rest.find("</math>").unwrap_or(rest.len());
+            if let Some(start) = rest[..end].find(TEX_START) {
+                let tex = &rest[start + TEX_START.len()..end];
+                text.push('$');
+                text.push_str(tex.split("</annotation>").next().unwrap_or(""));
+                text.push('$');
+            }
+            rest = rest.get(end + "</math>".len()..).unwrap_or("");
+        } else if hidden_spans > 0 {
+            if tag.starts_with("span") {
+                hidden_spans += 1;
+            } else if tag == "/span" {
+                hidden_spans -= 1;
+            }
+        } else if tag.starts_with("span") && tag.contains("katex-html") {
+            hidden_spans = 1;
+        } else if ["p", "/p", "div", "/div", "li", "br", "br/", "h1", "h2", "h3", "h4", "h5", "h6"].iter().any(|block| tag == *block || tag.starts_with(&format!("{} ", block))) {
+            text.push('\n');
+        }
+    }
+    if hidden_spans == 0 {
+        text.push_str(rest);
+    }
+
+    let text = text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'").replace("&nbsp;", " ").replace("&amp;", "&");
+    let lines: Vec<String> = text.lines().map(|line| line.split_whitespace().collect::<Vec<_>>().join(" ")).filter(|line| !line.is_empty()).collect();
+    return lines.join("\n");
+}
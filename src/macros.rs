@@ -1,23 +1,24 @@
 use crate::set_examples::render_venn;
 use palette::LabHue;
 use std::io;
-use std::fs;
 use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 use ropey::Rope;
 use thiserror::Error;
-use sourcefile::SourceFile;
+use crate::diagnostic::Diagnostic;
 use serde::Deserialize;
 use palette::{FromColor, Lch, Srgb};
 
-use crate::{Yatt, print_trace, CrefKind, BoxKind};
+use crate::{Yatt, State, normalize, preview_url, CrefKind, BoxKind, Reference, ReferenceKind, UserMacroInfo, UserMacroFrame, Verbosity};
+use crate::deferred::{check_source, contains_placeholder, Resolver};
+use crate::tex::TexJob;
 use crate::parse;
 use crate::parse::OffsetSpan;
 use crate::set_examples::{Term, render_equation};
 
 #[derive(Clone, Debug)]
-pub(crate) struct Trace(pub Option<OffsetSpan>);
+pub struct Trace(pub Option<OffsetSpan>);
 
 fn down_macro<D, P>(down: D, params: &P, args: Vec<OutInternal>, m_span: Trace, y: &mut Yatt) -> Result<Rope, ExpansionError>
 where
@@ -84,105 +85,147 @@ pub(crate) enum ExpansionError {
     TeX(katex::Error, Trace),
     #[error("never printed")]
     AlreadyMathmode(Trace),
+    #[error("never printed")]
+    EmptyMacroName(Trace),
+    #[error("never printed")]
+    DuplicateMacro(Trace /* definition */, Trace /* redefinition */),
+    #[error("never printed")]
+    RegisteredMacro(Trace, String /* name */),
+    #[error("never printed")]
+    ArgumentOutsideMacro(Trace),
+    #[error("never printed")]
+    ParameterIndex(usize, Trace),
+    #[error("never printed")]
+    OutputOutsideOutputDir(PathBuf, Trace),
+    #[error("never printed")]
+    NoOutput(Trace),
+    #[error("never printed")]
+    CyclicReferences(Vec<String>),
+    #[error("never printed")]
+    ReservedCharacter(char, Trace),
 }
 
+/// What a macro or cross reference that failed with a recoverable error turns into. Valid both in html and in TeX.
+pub(crate) const ERROR_PLACEHOLDER: &str = "??";
+
 impl ExpansionError {
-    pub fn print_expansion_error(&self, source: &SourceFile) {
+    // Whether expansion can continue after this error, using `ERROR_PLACEHOLDER` in place of whatever failed.
+    pub fn is_recoverable(&self) -> bool {
         match self {
-            ExpansionError::Parse(e) => {
-                e.print_parse_error(source);
-            }
+            ExpansionError::ArgumentNumber(..)
+            | ExpansionError::UnknownId(..)
+            | ExpansionError::UnknownDefine(..)
+            | ExpansionError::UnknownMathId(..)
+            | ExpansionError::UnknownTagId(..) => return true,
+            #[cfg(unix)]
+            ExpansionError::TeX(..) => return true,
+            _ => return false,
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        match self {
+            ExpansionError::Parse(e) => return e.diagnostic(),
             ExpansionError::ArgumentIndex(i, t) => {
-                println!("Buggy macro referred to argument number {}, but it did not get that many arguments.", i);
-                print_trace(t.clone(), source, false);
+                return Diagnostic::new("ArgumentIndex", format!("Buggy macro referred to argument number {}, but it did not get that many arguments.", i)).at(t.clone());
             }
             ExpansionError::ArgumentNumber(i, t) => {
-                println!("Macro received an invalid number of arguments ({}).", i);
-                print_trace(t.clone(), source, false);
+                return Diagnostic::new("ArgumentNumber", format!("Macro received an invalid number of arguments ({}).", i)).at(t.clone());
             }
             ExpansionError::InputIO(e, path, t) => {
-                println!("Failed to read input file {}:\n {}\n", path.to_string_lossy(), e);
-                print_trace(t.clone(), source, false);
+                return Diagnostic::new("InputIO", format!("Failed to read input file {}:", path.to_string_lossy())).note(format!(" {}\n", e)).at(t.clone());
             }
             ExpansionError::OutputIO(e, path, t) => {
-                println!("Failed to write output file {}:\n {}\n", path.to_string_lossy(), e);
-                print_trace(t.clone(), source, false);
+                return Diagnostic::new("OutputIO", format!("Failed to write output file {}:", path.to_string_lossy())).note(format!(" {}\n", e)).at(t.clone());
             }
             ExpansionError::CopyAll(e, from, to, t) => {
-                println!("Failed to copy file {} to {}:\n {}\n", from.to_string_lossy(), to.to_string_lossy(), e);
-                print_trace(t.clone(), source, false);
+                return Diagnostic::new("CopyAll", format!("Failed to copy file {} to {}:", from.to_string_lossy(), to.to_string_lossy())).note(format!(" {}\n", e)).at(t.clone());
             }
             ExpansionError::HSectionTooManyLevels(t) => {
-                println!("Cannot nest the `hsection` macro more than five times.");
-                print_trace(t.clone(), source, false);
+                return Diagnostic::new("HSectionTooManyLevels", "Cannot nest the `hsection` macro more than five times.").at(t.clone());
             }
             ExpansionError::CrefBoxlessDefinition(t) => {
-                println!("Cannot use `§cref` to reference a boxless definition");
-                print_trace(t.clone(), source, false);
+                return Diagnostic::new("CrefBoxlessDefinition", "Cannot use `§cref` to reference a boxless definition").at(t.clone());
             }
             ExpansionError::CaseNotInCases(t) => {
-                println!("Cannot use `§case` outside of `§cases`");
-                print_trace(t.clone(), source, false);
+                return Diagnostic::new("CaseNotInCases", "Cannot use `§case` outside of `§cases`").at(t.clone());
             }
             ExpansionError::DuplicateId(definition, redefinition) => {
-                println!("Cannot define the same id multiple times.");
-                println!("First definition:");
-                print_trace(definition.clone(), source, true);
-                println!("Second definition:");
-                print_trace(redefinition.clone(), source, true);
+                return Diagnostic::new("DuplicateId", "Cannot define the same id multiple times.")
+                    .labeled("First definition", definition.clone())
+                    .labeled("Second definition", redefinition.clone());
             }
             ExpansionError::UnknownId(id) => {
-                println!("Tried to reference undefined id.");
-                print_trace(id.clone(), source, true);
+                return Diagnostic::new("UnknownId", "Tried to reference undefined id.").at(id.clone());
             }
             ExpansionError::UnknownMathId(t, id) => {
-                println!("Must set the id for each math macro that links to a definition.");
-                println!("Macro: {}", id);
-                println!("At:");
-                print_trace(t.clone(), source, true);
+                return Diagnostic::new("UnknownMathId", "Must set the id for each math macro that links to a definition.")
+                    .note(format!("Macro: {}", id))
+                    .labeled("At", t.clone());
             }
             ExpansionError::UnknownTagId(t, id) => {
-                println!("Unknown tag id.");
-                println!("Id: {}", id);
-                println!("At:");
-                print_trace(t.clone(), source, true);
+                return Diagnostic::new("UnknownTagId", "Unknown tag id.").note(format!("Id: {}", id)).labeled("At", t.clone());
             }
             ExpansionError::DuplicateMathId(redefinition, id) => {
-                println!("Cannot define the id corresponding to a math macro multiple times.");
-                println!("Id: {}", id);
-                println!("Redefinition at:");
-                print_trace(redefinition.clone(), source, true);
+                return Diagnostic::new("DuplicateMathId", "Cannot define the id corresponding to a math macro multiple times.")
+                    .note(format!("Id: {}", id))
+                    .labeled("Redefinition at", redefinition.clone());
             }
             ExpansionError::DuplicateTagId(redefinition, id) => {
-                println!("Cannot define the id of a tag multiple times.");
-                println!("Id: {}", id);
-                println!("Redefinition at:");
-                print_trace(redefinition.clone(), source, true);
+                return Diagnostic::new("DuplicateTagId", "Cannot define the id of a tag multiple times.")
+                    .note(format!("Id: {}", id))
+                    .labeled("Redefinition at", redefinition.clone());
             }
             ExpansionError::DuplicateDefine(definition, redefinition) => {
-                println!("Cannot define the same name multiple times.");
-                println!("First definition:");
-                print_trace(definition.clone(), source, true);
-                println!("Second definition:");
-                print_trace(redefinition.clone(), source, true);
+                return Diagnostic::new("DuplicateDefine", "Cannot define the same name multiple times.")
+                    .labeled("First definition", definition.clone())
+                    .labeled("Second definition", redefinition.clone());
             }
             ExpansionError::UnknownDefine(id) => {
-                println!("Tried to reference undefined define.");
-                print_trace(id.clone(), source, true);
+                return Diagnostic::new("UnknownDefine", "Tried to reference undefined define.").at(id.clone());
             }
             ExpansionError::EmptyDefine(id) => {
-                println!("Cannot define the empty name.");
-                print_trace(id.clone(), source, true);
+                return Diagnostic::new("EmptyDefine", "Cannot define the empty name.").at(id.clone());
             }
             #[cfg(unix)]
             ExpansionError::TeX(e, t) => {
-                println!("Invalid tex input.\n");
-                println!("{:?}\n", e);
-                print_trace(t.clone(), source, false);
+                return Diagnostic::new("TeX", "Invalid tex input.\n").note(format!("{:?}\n", e)).at(t.clone());
             }
             ExpansionError::AlreadyMathmode(t) => {
-                println!("Cannot enter math mode while already in math mode.");
-                print_trace(t.clone(), source, false);
+                return Diagnostic::new("AlreadyMathmode", "Cannot enter math mode while already in math mode.").at(t.clone());
+            }
+            ExpansionError::ReservedCharacter(c, t) => {
+                return Diagnostic::new("ReservedCharacter", format!("The noncharacter U+{:04X} cannot occur in sources, it marks cross references during expansion.", *c as u32)).at(t.clone());
+            }
+            ExpansionError::EmptyMacroName(t) => {
+                return Diagnostic::new("EmptyMacroName", "Cannot define a macro with the empty name.").at(t.clone());
+            }
+            ExpansionError::DuplicateMacro(definition, redefinition) => {
+                return Diagnostic::new("DuplicateMacro", "Cannot define the same macro multiple times.")
+                    .labeled("First definition", definition.clone())
+                    .labeled("Second definition", redefinition.clone());
+            }
+            ExpansionError::RegisteredMacro(t, name) => {
+                return Diagnostic::new("RegisteredMacro", "Cannot define a macro that already exists.").note(format!("Macro: {}", name)).at(t.clone());
+            }
+            ExpansionError::ArgumentOutsideMacro(t) => {
+                return Diagnostic::new("ArgumentOutsideMacro", "Cannot use `§arg` or `§param` outside of the body of a `§defmacro`.").at(t.clone());
+            }
+            ExpansionError::ParameterIndex(i, t) => {
+                return Diagnostic::new("ParameterIndex", format!("Macro body referred to parameter number {}, but the macro did not get that many parameters.", i)).at(t.clone());
+            }
+            ExpansionError::OutputOutsideOutputDir(path, t) => {
+                return Diagnostic::new("OutputOutsideOutputDir", format!("Cannot write {} outside of the output directory.", path.to_string_lossy())).at(t.clone());
+            }
+            ExpansionError::NoOutput(t) => {
+                return Diagnostic::new("NoOutput", "Cannot define an id outside of any `§output`.").at(t.clone());
+            }
+            ExpansionError::CyclicReferences(cycle) => {
+                let mut d = Diagnostic::new("CyclicReferences", "Cross references depend on themselves:");
+                for description in cycle {
+                    d = d.note(format!("  {}", description));
+                }
+                return d;
             }
         }
     }
@@ -223,7 +266,7 @@ impl Out {
 }
 
 #[derive(Clone)]
-pub(crate) enum OutInternal {
+pub enum OutInternal {
     Many(Vec<OutInternal>),
     Text(Rope, Trace),
     EmptyMacro(Trace, (), Vec<OutInternal>),
@@ -237,6 +280,7 @@ pub(crate) enum OutInternal {
     HSection(Trace, HSection, Vec<OutInternal>, bool /*no numbering*/),
     Aside(Trace, (), Vec<OutInternal>, bool /*no numbering*/),
     ChapterNav(Trace, (), Vec<OutInternal>),
+    Toc(Trace, Toc, Vec<OutInternal>, bool /*only the current hsection*/),
     Box(Trace, BoxParams, Vec<OutInternal>, BoxKind, String, bool /*no numbering*/),
     Fact(Trace, BoxParams, Vec<OutInternal>, String, bool /*no numbering*/),
     Proof(Trace, Proof, Vec<OutInternal>),
@@ -280,6 +324,10 @@ pub(crate) enum OutInternal {
     PolarX(Trace, (f64, f64, f64), Vec<OutInternal>),
     PolarY(Trace, (f64, f64, f64), Vec<OutInternal>),
     TitledList(Trace, (), Vec<OutInternal>, &'static str),
+    DefMacro(Trace, DefMacro, Vec<OutInternal>),
+    UserMacro(Trace, UserMacro, Vec<OutInternal>, String /* name */, Trace /* name */),
+    MacroArgument(Trace, MacroArgument, Vec<OutInternal>),
+    MacroParameter(Trace, MacroArgument, Vec<OutInternal>),
 }
 
 impl OutInternal {
@@ -299,6 +347,16 @@ impl OutInternal {
 }
 
 pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionError> {
+    match expand_macro(out, y) {
+        Err(e) if e.is_recoverable() => {
+            y.state.errors.push(e);
+            return Ok(ERROR_PLACEHOLDER.into());
+        }
+        result => return result,
+    }
+}
+
+fn expand_macro(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionError> {
     match out {
         OutInternal::Text(r, _) => return Ok(r),
 
@@ -342,11 +400,12 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
                 y.state.current_file = y.state.base_dir().join(path);
             }
 
-            match fs::read_to_string(&y.state.current_file) {
+            y.dependencies.insert(y.state.current_file.clone());
+            match y.state.fs.read_to_string(&y.state.current_file) {
                 Err(e) => return Err(ExpansionError::InputIO(e, y.state.current_file.clone(), span)),
                 Ok(entry) => {
-                    let source_offset = y.source.contents.len();
-                    y.source.add_file_raw(&y.state.entrypoint.to_string_lossy(), &entry);
+                    let source_offset = y.source.add_file(&y.state.current_file, &entry, span.clone());
+                    check_source(&entry, source_offset)?;
                     let ast = parse::parse(&entry, y, source_offset)?;
                     let r = expand(ast, y)?;
                     y.state.current_file = old_current_file;
@@ -362,38 +421,17 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
 
             let path = &path[0];
 
-            let p = if path.is_absolute() {
-                y.state.base_dir().join("build/").join(&path.strip_prefix("/").unwrap())
-            } else {
-                y.state.cwd().join("build/").join(path)
-            };
-
-            let mut dirname = p.clone();
-            dirname.pop();
-            let _ = fs_extra::dir::create_all(dirname, false);
+            let p = y.state.resolve_output(path, &span)?;
 
             y.state.current_output = p.clone();
 
             let r = up_macro(|_path, args, y, span| {
-                let content = args[0].to_string();
-
-                if y.state.second_iteration {
-                    match fs::write(&p, &content) {
-                        Err(e) => return Err(ExpansionError::OutputIO(e, p.clone(), span)),
-                        Ok(()) => {
-                            if tee {
-                                return Ok(args[0].clone());
-                            } else {
-                                return Ok(Rope::new());
-                            }
-                        }
-                    }
+                y.state.write(p.clone(), args[0].to_string(), span);
+
+                if tee {
+                    return Ok(args[0].clone());
                 } else {
-                    if tee {
-                        return Ok(args[0].clone());
-                    } else {
-                        return Ok(Rope::new());
-                    }
+                    return Ok(Rope::new());
                 }
             }, &path, args, span, y);
 
@@ -405,32 +443,35 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
         OutInternal::CopyAll(span, params, args) => {
             arguments_exact(0, &args, &span)?;
 
-            if y.state.second_iteration {
-                let from = &params[0];
-                let to = PathBuf::from("build/").join(&params[1]);
-
-                let mut opts = fs_extra::dir::CopyOptions::new();
-                opts.copy_inside = true;
-                opts.overwrite = true;
+            let from = y.state.base_dir().join(&params[0]);
+            let to = normalize(&y.state.output_dir.join(&params[1]));
+            if !to.starts_with(&y.state.output_dir) {
+                return Err(ExpansionError::OutputOutsideOutputDir(params[1].clone(), span));
+            }
+            y.dependencies.insert(from.clone());
 
-                fs_extra::copy_items(&params[0..1], &to, &opts)
-                .map_err(|e| ExpansionError::CopyAll(e, from.clone(), to.clone(), span))?;
+            if y.state.verbosity >= Verbosity::Verbose {
+                println!("Copying {} into {}", from.to_string_lossy(), to.to_string_lossy());
             }
 
+            y.state.fs.copy_into(&from, &to)
+            .map_err(|e| ExpansionError::CopyAll(e, from.clone(), to.clone(), span))?;
+            y.state.copies.push((from, to));
+
             return Ok(Rope::new());
         }
 
         OutInternal::Template(trace, params, args) => {
             arguments_exact(2, &args, &trace)?;
-            return down_macro(|_p, _n, _y, _trace| {
-                let outs = vec![
-                    Out::Text(r###"<!DOCTYPE html>
+            return down_macro(|_p, _n, y, _trace| {
+                let mut outs = vec![
+                    Out::Text(format!(r###"<!DOCTYPE html>
 <html>
     <head>
         <meta charset="UTF-8">
         <link rel="stylesheet" href="./assets/katex.min.css">
         <link rel="stylesheet" href="./assets/fonts.css">
-        <link rel="stylesheet" href="./assets/main.css">"###.into()),
+        <link rel="stylesheet" href="./{}">"###, y.state.theme).into()),
                     Out::Argument(0),
                     Out::Text(r###"
     </head>
@@ -445,12 +486,17 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
 "###.into()),
                     Out::Argument(1),
                     Out::Text(r###"
-            </div>
-    </body>
-</html>
-"###.into()),
+            </div>"###.into()),
                 ];
 
+                if y.live_reload {
+                    outs.push(Out::Text(crate::serve::LIVE_RELOAD_SCRIPT.into()));
+                }
+
+                outs.push(Out::Text(r###"
+    </body>
+</html>
+"###.into()));
 
                 return Ok(Out::Many(outs));
             }, &params, args, trace, y);
@@ -483,14 +529,22 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
                         if args.len() == 1 {args[0].to_string()} else {args[args.len() - 2].to_string()},
                         post,
                     );
-                    let opts = katex::Opts::builder()
-                        .display_mode(display)
-                        .throw_on_error(true)
-                        .trust(true)
-                        .fleqn(y.state.fleqn)
-                        .build().unwrap();
-
-                    return Ok(katex::render_with_opts(&content, &opts).map_err(|e| ExpansionError::TeX(e, span.clone()))?.into());
+                    let fleqn = y.state.fleqn;
+
+                    let job = y.state.tex_jobs.len();
+                    y.state.tex_jobs.push(TexJob { content, display, fleqn, trust: true });
+                    return Ok(y.state.defer("math", move |state, resolver| {
+                        let rendered = match state.rendered_tex.get(job) {
+                            Some(Some(rendered)) => rendered.clone(),
+                            // KaTeX must see the actual cross references, render once they are known.
+                            _ => {
+                                let mut job = state.tex_jobs[job].clone();
+                                job.content = resolver.resolve(state, &job.content)?;
+                                state.tex_cache.borrow_mut().render(job)
+                            }
+                        };
+                        return rendered.map_err(|e| ExpansionError::TeX(e, span.clone()));
+                    }).into());
                 }
 
                 #[cfg(not(unix))]
@@ -515,7 +569,9 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
             arguments_exact(1, &args, &span)?;
 
             return up_macro(|_, args, y, _span| {
-                y.state.domain = args[0].to_string();
+                if y.domain.is_none() {
+                    y.state.domain = args[0].to_string();
+                }
                 return Ok("".into());
             }, &path, args, span, y);
         }
@@ -560,10 +616,7 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
 
             let id_trace = args[0].trace();
 
-            y.state.sticky_state.hsections_structure.push(
-                params.0[0].to_string(),
-                y.state.second_iteration,
-            );
+            y.state.sticky_state.hsections_structure.push(params.0[0].to_string());
 
             let r = up_macro(|p, args, y, _trace| {
                 let url = y.state.register_id(&p.0[0].to_string(), CrefKind::HSection, id_trace.clone())?;
@@ -600,8 +653,23 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
             return r;
         }
 
+        OutInternal::Toc(trace, params, args, local) => {
+            arguments_exact(0, &args, &trace)?;
+
+            let path = if local { y.state.sticky_state.hsections_structure.current_path() } else { Vec::new() };
+            let depth = params.0[0];
+            let domain = y.state.domain.clone();
+
+            return Ok(y.state.defer("table of contents", move |state, _| {
+                return Ok(format!(r###"<nav class="toc">{}</nav>"###, toc(state, &domain, &path, depth, &trace)?));
+            }).into());
+        }
+
         OutInternal::Toggled(trace, params, args, invisible, visible) => {
             arguments_exact(1, &args, &trace)?;
+            if let Some(id) = &y.state.box_current {
+                y.state.sticky_state.solved.insert(id.clone());
+            }
             return down_macro(|p, _n, _y, _trace| {
                 return Ok(Out::Many(vec![
                         Out::Text(format!(r###"<button class="toggle no" id="btn_toggle_{}">{}</button><div class="toggled" style="display: none">"###, p.0[0], invisible).into()),
@@ -693,23 +761,26 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
         OutInternal::ChapterNav(trace, params, args) => {
             arguments_exact(0, &args, &trace)?;
             return down_macro(|_p, _n, y, trace| {
-                if y.state.second_iteration {
-                    let (previous, next) = y.state.sticky_state.hsections_structure.previous_and_next_ids();
+                let path = y.state.sticky_state.hsections_structure.current_path();
+                let domain = y.state.domain.clone();
+
+                let nav = y.state.defer("chapter navigation", move |state, _| {
+                    let (previous, next) = state.sticky_state.hsections_structure.previous_and_next_ids(&path);
 
                     let prev_link = if let Some(id) = previous {
-                        let info = y.state.sticky_state.hsections.get(id).unwrap();
-                        format!(r###"<a href="{}">{}</a>"###, y.state.resolve_id_to_url(id, trace.clone())?, info.title)
+                        let info = state.sticky_state.hsections.get(id).unwrap();
+                        format!(r###"<a href="{}">{}</a>"###, state.url_of_id(&domain, id, &trace)?, info.title)
                     } else {
                         "".to_string()
                     };
                     let next_link = if let Some(id) = next {
-                        let info = y.state.sticky_state.hsections.get(id).unwrap();
-                        format!(r###"<a href="{}">{}</a>"###, y.state.resolve_id_to_url(id, trace.clone())?, info.title)
+                        let info = state.sticky_state.hsections.get(id).unwrap();
+                        format!(r###"<a href="{}">{}</a>"###, state.url_of_id(&domain, id, &trace)?, info.title)
                     } else {
                         "".to_string()
                     };
 
-                    let nav = format!(
+                    return Ok(format!(
                         r###"<nav class="chapter_navigation slightlywide">
     <div class="previous_chapter">
         {}
@@ -720,14 +791,12 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
     </div>
 </nav>"###,
                         prev_link,
-                        y.state.resolve_id_to_url("toc", trace)?,
+                        state.url_of_id(&domain, "toc", &trace)?,
                         next_link,
-                    );
+                    ));
+                });
 
-                    return Ok(Out::Text(nav.into()));
-                } else {
-                    return Ok(Out::Many(vec![]))
-                }
+                return Ok(Out::Text(nav.into()));
             }, &params, args, trace, y);
         }
 
@@ -793,7 +862,7 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
             let id = params.0[0].clone();
             y.state.box_current = Some(id.to_string());
 
-            let id_trace = Trace(None);
+            let id_trace = trace.clone();
             let r = up_macro(|p, args, y, _trace| {
                 let url = y.state.register_id(&id.clone(), CrefKind::Box, id_trace.clone())?;
                 let classes = p.0.get(1).map(|s| s.to_string()).unwrap_or(String::new());
@@ -854,7 +923,7 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
             let id = params.0[0].clone();
             y.state.box_current = Some(id.to_string());
 
-            let id_trace = Trace(None);
+            let id_trace = trace.clone();
             let r = up_macro(|_p, args, y, _trace| {
                 let url = y.state.register_id(&id.clone(), CrefKind::Box, id_trace.clone())?;
                 y.state.sticky_state.boxes.insert(id.to_string(), crate::BoxInfo {
@@ -898,7 +967,7 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
             let id = if params.0.len() == 1 {format!("proof_{}", params.0[0])} else {params.0[2].to_string()};
             y.state.box_current = Some(id.to_string());
 
-            let id_trace = Trace(None);
+            let id_trace = trace.clone();
             let r = up_macro(|p, args, y, _trace| {
                 let url = y.state.register_id(&id.clone(), CrefKind::Box, id_trace.clone())?;
                 y.state.sticky_state.boxes.insert(id.to_string(), crate::BoxInfo {
@@ -949,105 +1018,18 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
 
             let id_trace = args[0].trace();
 
-            if y.state.second_iteration {
-                return up_macro(|_p, args, y, _trace| {
-                    let id = &args[0];
-                    match y.state.sticky_state.ids.get(&id.to_string()) {
-                        None => return Err(ExpansionError::UnknownId(id_trace.clone())),
-                        Some(info) => {
-                            let url = y.state.resolve_id_to_url(id, id_trace.clone())?;
-
-                            match info.kind {
-                                CrefKind::HSection => {
-                                    let hsection_info = y.state.sticky_state.hsections.get(&id.to_string()).unwrap();
-                                    let label = if args.len() == 2 {
-                                        args[1].to_string()
-                                    } else {
-                                        format!("{}&nbsp;{}", hsection_info.name, hsection_info.numbering)
-                                    };
-                                    let tag = format!(
-                                        r###"<a class="ref" href="{}">{}</a>"###,
-                                        url,
-                                        label,
-                                    );
-                                    return Ok(tag.into());
-                                }
-
-                                CrefKind::Case => {
-                                    let numbering = y.state.sticky_state.cases.get(&id.to_string()).unwrap();
-                                    let label = if args.len() == 2 {
-                                        args[1].to_string()
-                                    } else {
-                                        format!("Case&nbsp;{}", numbering)
-                                    };
-                                    let tag = format!(
-                                        r###"<a class="ref" href="{}">{}</a>"###,
-                                        url,
-                                        label,
-                                    );
-                                    return Ok(tag.into());
-                                }
-
-                                CrefKind::Box => {
-                                    let box_info = y.state.sticky_state.boxes.get(&id.to_string()).unwrap();
-                                    match box_info.kind {
-                                        BoxKind::Proof => {
-                                            let claim_name = y.state.claim_name(&id.to_string(), id_trace.clone())?;
-                                            let tag = format!(
-                                                r###"<a class="ref {}" href="{}" data-preview="{}">proof of {}</a>"###,
-                                                box_info.kind.class(),
-                                                url,
-                                                y.state.id_to_preview_url(id),
-                                                claim_name,
-                                            );
-                                            return Ok(tag.into());
-                                        }
-                                        _ => {
-                                            if y.state.mathmode {
-                                                let label = if args.len() == 2 {
-                                                    args[1].to_string()
-                                                } else {
-                                                    format!("{}~{}", box_info.name, box_info.numbering)
-                                                };
-
-                                                let tex = format!(
-                                                    r###"\href{{{}}}{{\htmlClass{{ref {}}}{{\htmlData{{preview={}, width={}}}{{{}}}}}}}"###,
-                                                    url,
-                                                    box_info.kind.class(),
-                                                    y.state.id_to_preview_url(id),
-                                                    box_info.classes,
-                                                    label,
-                                                );
-                                                return Ok(tex.into());
-                                            } else {
-                                                let label = if args.len() == 2 {
-                                                    args[1].to_string()
-                                                } else {
-                                                    format!("{}&nbsp;{}", box_info.name, box_info.numbering)
-                                                };
-
-                                                let tag = format!(
-                                                    r###"<a class="ref {}" href="{}" data-preview="{}" data-width="{}">{}</a>"###,
-                                                    box_info.kind.class(),
-                                                    url,
-                                                    y.state.id_to_preview_url(id),
-                                                    box_info.classes,
-                                                    label,
-                                                );
-                                                return Ok(tag.into());
-                                            }
-                                        }
-                                    }
-                                }
-
-                                CrefKind::BoxlessDefinition => return Err(ExpansionError::CrefBoxlessDefinition(id_trace.clone())),
-                            }
-                        }
-                    }
-                }, &params, args, trace, y);
-            } else {
-                return Ok(Rope::new());
-            }
+            return up_macro(|_p, args, y, trace| {
+                let id = args[0].to_string();
+                let label = if args.len() == 2 { Some(args[1].to_string()) } else { None };
+                let domain = y.state.domain.clone();
+                let mathmode = y.state.mathmode;
+                let id_trace = id_trace.clone();
+                y.state.references.push(Reference { kind: ReferenceKind::Id, name: id.clone(), trace: id_trace.clone() });
+
+                return Ok(y.state.defer(format!("`§cref` to `{}`", id), move |state, _| {
+                    return cref(state, &domain, &id, label.clone(), mathmode, &trace, &id_trace);
+                }).into());
+            }, &params, args, trace, y);
         }
 
         OutInternal::Define(trace, params, args, custom_text) => {
@@ -1061,16 +1043,13 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
                 };
 
                 if custom_text {
-                    y.state.register_id(&target_id.clone(), CrefKind::BoxlessDefinition, Trace(None))?;
+                    y.state.register_id(&target_id.clone(), CrefKind::BoxlessDefinition, trace.clone())?;
 
-                    if y.state.second_iteration {
-                        let _ = fs_extra::dir::create_all(y.state.base_dir().join("build/previews/"), false);
-                        let p = y.state.base_dir().join(format!(r#"build/previews/{}.html"#, target_id));
-                        let _ = std::fs::write(&p, format!(r###"<article>{}</article>"###, &args[args.len() - 1])).map_err(|e| ExpansionError::OutputIO(e, p.clone(), Trace(None)))?;
-                    }
+                    let p = y.state.output_dir.join(format!(r#"previews/{}.html"#, target_id));
+                    y.state.write(p, format!(r###"<article>{}</article>"###, &args[args.len() - 1]), Trace(None));
                 } else {
                     if boxless {
-                        y.state.register_id(&target_id.clone(), CrefKind::BoxlessDefinition, Trace(None))?;
+                        y.state.register_id(&target_id.clone(), CrefKind::BoxlessDefinition, trace.clone())?;
                         y.state.boxless_previews.insert(target_id.to_string());
                     } else {
                         y.state.box_previews.insert(target_id.to_string());
@@ -1086,11 +1065,11 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
                     format!(
                         "{}{}#{}",
                         y.state.domain,
-                        y.state.current_output_relative().to_string_lossy(),
+                        y.state.current_output_relative(&trace)?.to_string_lossy(),
                         target_id,
                     )
                 } else {
-                    y.state.resolve_id_to_url(&target_id, Trace(None))?
+                    y.state.resolve_id_to_url(&target_id, Trace(None))
                 };
                 let preview_url = y.state.id_to_preview_url(target_id.clone());
                 let singular = args[0].to_string();
@@ -1117,58 +1096,21 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
 
             let id_trace = args[0].trace();
 
-            if y.state.second_iteration {
-                return up_macro(|_p, args, y, _trace| {
-                    let id = args[0].to_string();
-
-                    match y.state.sticky_state.defined.get(&id) {
-                        None => return Err(ExpansionError::UnknownDefine(id_trace.clone())),
-                        Some(info) => {
-                            let name = if args.len() == 2 {
-                                args[1].to_string()
-                            } else {
-                                let tmp = if pluralize {
-                                    &info.plural
-                                } else {
-                                    &info.singular
-                                };
-                                if capitalize {
-                                    some_kind_of_uppercase_first_letter(tmp)
-                                } else {
-                                    tmp.to_string()
-                                }
-                            };
-
-                            if fakedef {
-                                return Ok(format!(
-                                    r###"<dfn id="{}"><a href="{}">{}</a></dfn>"###,
-                                    &id,
-                                    y.state.resolve_defined_to_preview_url(&id, id_trace.clone())?,
-                                    name,
-                                ).into());
-                            } else {
-                                if y.state.mathmode {
-                                    return Ok(format!(
-                                        r###"\href{{{}}}{{\htmlClass{{ref definition}}{{\htmlData{{preview={}}}{{{}}}}}}}"###,
-                                        info.href,
-                                        y.state.resolve_defined_to_preview_url(id, id_trace.clone())?,
-                                        name,
-                                    ).into());
-                                } else {
-                                    return Ok(format!(
-                                        r###"<a class="ref definition" href="{}" data-preview="{}">{}</a>"###,
-                                        info.href,
-                                        y.state.resolve_defined_to_preview_url(id, id_trace.clone())?,
-                                        name,
-                                    ).into());
-                                }
-                            }
-                        }
-                    }
-                }, &params, args, trace, y);
-            } else {
-                return Ok(Rope::new());
-            }
+            return up_macro(|_p, args, y, _trace| {
+                let id = args[0].to_string();
+                let name = if args.len() == 2 { Some(args[1].to_string()) } else { None };
+                let mathmode = y.state.mathmode;
+                let id_trace = id_trace.clone();
+                y.state.references.push(Reference { kind: ReferenceKind::Define, name: id.clone(), trace: id_trace.clone() });
+
+                return Ok(y.state.defer(format!("reference to define `{}`", id), move |state, resolver| {
+                    let name = match &name {
+                        Some(name) => name.clone(),
+                        None => defined_name(state, resolver, &id, capitalize, pluralize, &id_trace)?,
+                    };
+                    return reference_defined(state, &id, name, fakedef, mathmode, &id_trace);
+                }).into());
+            }, &params, args, trace, y);
         }
 
         OutInternal::TitledList(trace, params, args, title) => {
@@ -1183,7 +1125,7 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
                 return Ok(Out::Many(vec![
                         Out::Text("<div>".into()),
                         Out::Text(title.into()),
-                        Out::Text("</ul>".into()),
+                        Out::Text("<ul>".into()),
                         Out::Many(items),
                         Out::Text("</ul>".into()),
                         Out::Text("</div>".into()),
@@ -1220,14 +1162,12 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
         OutInternal::Link(trace, params, args) => {
             arguments_exact(2, &args, &trace)?;
 
-            return down_macro(|_p, _n, _y, _trace| {
-                return Ok(Out::Many(vec![
-                        Out::Text(r###"<a class="outlink" href=""###.into()),
-                        Out::Argument(1),
-                        Out::Text(r###"">"###.into()),
-                        Out::Argument(0),
-                        Out::Text(r###"</a>"###.into()),
-                    ]));
+            return up_macro(|_p, args, y, trace| {
+                let url = args[1].to_string();
+                if !contains_placeholder(&url) {
+                    y.state.traced_url(url.clone(), &trace);
+                }
+                return Ok(format!(r###"<a class="outlink" href="{}">{}</a>"###, url, args[0]).into());
             }, &params, args, trace, y);
         }
 
@@ -1248,162 +1188,115 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
         OutInternal::SetMathId(trace, params, args) => {
             arguments_exact(0, &args, &trace)?;
 
-            if y.state.second_iteration {
-                return Ok(Rope::new());
-            } else {
-                match y.state.sticky_state.math_definitions.insert(params.0[0].clone(), params.0[1].to_string()) {
-                    None => return Ok(Rope::new()),
-                    Some(_) => return Err(ExpansionError::DuplicateMathId(trace.clone(), params.0[0].to_string())),
-                }
+            match y.state.sticky_state.math_definitions.insert(params.0[0].clone(), (params.0[1].to_string(), trace.clone())) {
+                None => return Ok(Rope::new()),
+                Some(_) => return Err(ExpansionError::DuplicateMathId(trace.clone(), params.0[0].to_string())),
             }
         }
 
         OutInternal::SetTag(trace, params, args, clever) => {
             arguments_exact(1, &args, &trace)?;
 
-            if y.state.second_iteration {
-                return Ok(Rope::new());
-            } else {
-                return up_macro(|_, args, y, _span| {
-                    match y.state.sticky_state.tag_definitions.insert(params.0[0].clone(), (args[0].clone(), clever)) {
-                        None => return Ok(Rope::new()),
-                        Some(_) => return Err(ExpansionError::DuplicateTagId(trace.clone(), params.0[0].to_string())),
-                    }
-                }, &params, args, trace.clone(), y);
-            }
+            return up_macro(|_, args, y, _span| {
+                match y.state.sticky_state.tag_definitions.insert(params.0[0].clone(), (args[0].clone(), clever, trace.clone())) {
+                    None => return Ok(Rope::new()),
+                    Some(_) => return Err(ExpansionError::DuplicateTagId(trace.clone(), params.0[0].to_string())),
+                }
+            }, &params, args, trace.clone(), y);
         }
 
         OutInternal::RTag(trace, params, args) => {
             arguments_exact(0, &args, &trace)?;
 
-            if y.state.second_iteration {
-                match y.state.sticky_state.tag_definitions.get(&params.0[0]) {
-                    None => return Err(ExpansionError::UnknownTagId(trace, params.0[0].to_string())),
-                    Some((tag, clever)) => {
-                        let tag = tag.clone();
-                        let clever = clever.clone();
-
-                        return down_macro(|p, _n, _y, _trace| {
-                            if clever {
-                                return Ok(Out::Cref(Cref, vec![Out::Text(p.0[0].clone().into()), Out::Text(tag.clone())]));
-                            } else {
-                                return Ok(Out::ReferenceDefined(vec![Out::Text(p.0[0].clone().into()), Out::Text(tag.clone())], false, false, false));
-                            }
-                        }, &params, args, trace.clone(), y);
+            let id = params.0[0].to_string();
+            let domain = y.state.domain.clone();
+            let mathmode = y.state.mathmode;
+            y.state.references.push(Reference { kind: ReferenceKind::Tag, name: id.clone(), trace: trace.clone() });
+
+            return Ok(y.state.defer(format!("`§rtag` to `{}`", id), move |state, _| {
+                match state.sticky_state.tag_definitions.get(&id) {
+                    None => return Err(ExpansionError::UnknownTagId(trace.clone(), id.clone())),
+                    Some((tag, clever, _)) => {
+                        if *clever {
+                            return cref(state, &domain, &id, Some(tag.to_string()), mathmode, &trace, &trace);
+                        } else {
+                            return reference_defined(state, &id, tag.to_string(), false, mathmode, &trace);
+                        }
                     }
                 }
-            } else {
-                return Ok(Rope::new());
-            }
+            }).into());
         }
 
         OutInternal::MathMacro(trace, params, args, math_id, tex) => {
             arguments_exact(0, &args, &trace)?;
 
-            if y.state.second_iteration {
-                match y.state.sticky_state.math_definitions.get(&math_id) {
-                    None => return Err(ExpansionError::UnknownMathId(trace, math_id.to_string())),
-                    Some(id) => {
-                        let url = y.state.resolve_id_to_url(id, trace)?;
-                        let preview_url = y.state.id_to_preview_url(id);
-
-                        if params.0[0] {
-                            return Ok(format!(r###"\htmlData{{preview={}}}{{{}}}"###, preview_url, tex).into());
-                        } else {
-                            return Ok(format!(r###"\htmlData{{preview={}}}{{\href{{{}}}{{{}}}}}"###, preview_url, url, tex).into());
-                        }
-                    }
+            let preview_only = params.0[0];
+            return Ok(y.state.defer_math_link(math_id, trace, move |url, preview_url| {
+                if preview_only {
+                    return format!(r###"\htmlData{{preview={}}}{{{}}}"###, preview_url, tex);
+                } else {
+                    return format!(r###"\htmlData{{preview={}}}{{\href{{{}}}{{{}}}}}"###, preview_url, url, tex);
                 }
-            } else {
-                return Ok(Rope::new());
-            }
+            }).into());
         }
 
         OutInternal::EncloseMath(trace, params, args, math_id, pre, post) => {
             arguments_exact(1, &args, &trace)?;
 
             return down_macro(|_p, _n, y, trace| {
-                if y.state.second_iteration {
-                    match y.state.sticky_state.math_definitions.get(math_id) {
-                        None => return Err(ExpansionError::UnknownMathId(trace, math_id.to_string())),
-                        Some(id) => {
-                            let url = y.state.resolve_id_to_url(id, trace)?;
-                            let preview_url = y.state.id_to_preview_url(id);
-
-                            let pre_tex = format!(r###"\htmlData{{preview={}}}{{\href{{{}}}{{{}}}}}"###, preview_url, url, pre);
-                            let post_tex = format!(r###"\htmlData{{preview={}}}{{\href{{{}}}{{{}}}}}"###, preview_url, url, post);
-
-                            return Ok(Out::Many(vec![
-                                    Out::Text(pre_tex.into()),
-                                    Out::Argument(0),
-                                    Out::Text(post_tex.into()),
-                                ]));
-                        }
-                    }
-                } else {
-                    return Ok(Out::Argument(0));
-                }
+                let pre_tex = y.state.defer_math_link(math_id, trace.clone(), move |url, preview_url| {
+                    return format!(r###"\htmlData{{preview={}}}{{\href{{{}}}{{{}}}}}"###, preview_url, url, pre);
+                });
+                let post_tex = y.state.defer_math_link(math_id, trace, move |url, preview_url| {
+                    return format!(r###"\htmlData{{preview={}}}{{\href{{{}}}{{{}}}}}"###, preview_url, url, post);
+                });
+
+                return Ok(Out::Many(vec![
+                        Out::Text(pre_tex.into()),
+                        Out::Argument(0),
+                        Out::Text(post_tex.into()),
+                    ]));
             }, &params, args, trace, y);
         }
 
         OutInternal::EncloseFunctionApplication(trace, params, args, math_id, name) => {
             let len = args.len();
             return down_macro(|p, _n, y, trace| {
-                if y.state.second_iteration {
-                    match y.state.sticky_state.math_definitions.get(&math_id) {
-                        None => return Err(ExpansionError::UnknownMathId(trace, math_id.to_string())),
-                        Some(id) => {
-                            let url = y.state.resolve_id_to_url(id, trace)?;
-                            let preview_url = y.state.id_to_preview_url(id);
-
-                            let name_tex = format!(r###"\htmlData{{preview={}}}{{\href{{{}}}{{{}}}}}"###, preview_url, url, name);
-
-                            let outs = (0..len).map(|i| Out::Argument(i)).collect();
-                            return Ok(Out::Many(vec![
-                                    Out::Text(name_tex.into()),
-                                    Out::MathFunctionParens(p.clone(), outs)
-                                ]));
-                        }
-                    }
-                } else {
-                    return Ok(Out::Argument(0));
-                }
+                let name_tex = y.state.defer_math_link(math_id.clone(), trace, move |url, preview_url| {
+                    return format!(r###"\htmlData{{preview={}}}{{\href{{{}}}{{{}}}}}"###, preview_url, url, name);
+                });
+
+                let outs = (0..len).map(|i| Out::Argument(i)).collect();
+                return Ok(Out::Many(vec![
+                        Out::Text(name_tex.into()),
+                        Out::MathFunctionParens(p.clone(), outs)
+                    ]));
             }, &params, args, trace, y);
         }
 
         OutInternal::MathSet(trace, params, args) => {
             return down_macro(|p, n, y, trace| {
-                if y.state.second_iteration {
-                    match y.state.sticky_state.math_definitions.get("set") {
-                        None => return Err(ExpansionError::UnknownMathId(trace, "set".to_string())),
-                        Some(id) => {
-                            let url = y.state.resolve_id_to_url(id, trace)?;
-                            let preview_url = y.state.id_to_preview_url(id);
-                            if n == 0 {
-                                return Ok(Out::Text(format!(r###"\htmlData{{preview={}}}{{\href{{{}}}{{\emptyset}}}}"###, preview_url, url).into()));
-                            } else {
-                                let (sizing_left, sizing_right) = sizing_level(p.0[0]);
-                                let left_delimiter = format!(r###" {}\lbrace "###, sizing_left);
-                                let right_delimiter = format!(r###" {}\rbrace "###, sizing_right);
-
-                                let mut outs = vec![Out::Text(left_delimiter.into())];
-                                for i in 0..n {
-                                    if i != 0 {
-                                        outs.push(Out::Text(r###", \allowbreak"###.into()));
-                                    }
-                                    outs.push(Out::Argument(i));
-                                }
-                                outs.push(Out::Text(right_delimiter.into()));
-
-                                return Ok(Out::Many(outs));
-                            }
-                        }
-                    }
+                if n == 0 {
+                    return Ok(Out::Text(y.state.defer_math_link("set", trace, |url, preview_url| {
+                        return format!(r###"\htmlData{{preview={}}}{{\href{{{}}}{{\emptyset}}}}"###, preview_url, url);
+                    }).into()));
                 } else {
-                    let mut outs = vec![];
+                    // Only the empty set links to the definition, but the math id must exist regardless.
+                    y.state.defer_math_link("set", trace, |_, _| String::new());
+
+                    let (sizing_left, sizing_right) = sizing_level(p.0[0]);
+                    let left_delimiter = format!(r###" {}\lbrace "###, sizing_left);
+                    let right_delimiter = format!(r###" {}\rbrace "###, sizing_right);
+
+                    let mut outs = vec![Out::Text(left_delimiter.into())];
                     for i in 0..n {
+                        if i != 0 {
+                            outs.push(Out::Text(r###", \allowbreak"###.into()));
+                        }
                         outs.push(Out::Argument(i));
                     }
+                    outs.push(Out::Text(right_delimiter.into()));
+
                     return Ok(Out::Many(outs));
                 }
             }, &params, args, trace, y);
@@ -1805,6 +1698,71 @@ pub(crate) fn expand(out: OutInternal, y: &mut Yatt) -> Result<Rope, ExpansionEr
             return Ok((r * radians.sin() + y).to_string().into());
         }
 
+        OutInternal::DefMacro(trace, params, mut args) => {
+            arguments_exact(1, &args, &trace)?;
+
+            let DefMacro(name, arguments) = params;
+            if name == "" {
+                return Err(ExpansionError::EmptyMacroName(trace));
+            } else if y.macros.contains(&name) {
+                return Err(ExpansionError::RegisteredMacro(trace, name));
+            }
+
+            match y.state.user_macros.insert(name, UserMacroInfo {
+                definition: trace.clone(),
+                arguments,
+                body: args.pop().unwrap(),
+            }) {
+                Some(info) => return Err(ExpansionError::DuplicateMacro(info.definition, trace)),
+                None => return Ok(Rope::new()),
+            }
+        }
+
+        OutInternal::UserMacro(trace, params, args, name, name_trace) => {
+            let (arguments, body) = match y.state.user_macros.get(&name) {
+                None => return Err(ExpansionError::Parse(parse::ParseError::UnknownMacroName(name_trace))),
+                Some(info) => (info.arguments, info.body.clone()),
+            };
+            arguments_exact(arguments, &args, &trace)?;
+
+            y.state.user_macro_frames.push(UserMacroFrame {
+                args,
+                params: params.0,
+            });
+            let r = expand(body, y);
+            y.state.user_macro_frames.pop();
+
+            return r;
+        }
+
+        OutInternal::MacroArgument(trace, params, args) => {
+            arguments_exact(0, &args, &trace)?;
+
+            // The argument was written in the context of the caller, so it is expanded without the current frame.
+            let frame = match y.state.user_macro_frames.pop() {
+                None => return Err(ExpansionError::ArgumentOutsideMacro(trace)),
+                Some(frame) => frame,
+            };
+            let r = down_macro(|p, _n, _y, _trace| {
+                return Ok(Out::Argument(p.0[0]));
+            }, &params, frame.args.clone(), trace, y);
+            y.state.user_macro_frames.push(frame);
+
+            return r;
+        }
+
+        OutInternal::MacroParameter(trace, params, args) => {
+            arguments_exact(0, &args, &trace)?;
+
+            match y.state.user_macro_frames.last() {
+                None => return Err(ExpansionError::ArgumentOutsideMacro(trace)),
+                Some(frame) => match frame.params.get(params.0[0]) {
+                    None => return Err(ExpansionError::ParameterIndex(params.0[0], trace)),
+                    Some(param) => return Ok(param.as_str().into()),
+                }
+            }
+        }
+
     }
 }
 
@@ -1941,6 +1899,188 @@ fn html_tag(tag: &str, params: &TagParams, args: usize, _y: &mut Yatt, trace: Tr
     }
 }
 
+// Nested lists of links to the hsections inside of the one at `path`, `depth` levels deep, or all the way for zero.
+// Numbers are shown wherever the headings show them.
+fn toc(state: &State, domain: &str, path: &[usize], depth: usize, trace: &Trace) -> Result<String, ExpansionError> {
+    let children = state.sticky_state.hsections_structure.children(path);
+    if children.is_empty() {
+        return Ok(String::new());
+    }
+
+    let level = path.len() + 1;
+    let mut html = "<ol>".to_string();
+    for (i, id) in children.into_iter().enumerate() {
+        let info = match state.sticky_state.hsections.get(id) {
+            None => continue, // failed to expand
+            Some(info) => info,
+        };
+
+        let subsections = if depth == 1 {
+            String::new()
+        } else {
+            let mut child_path = path.to_vec();
+            child_path.push(i);
+            toc(state, domain, &child_path, depth.saturating_sub(1), trace)?
+        };
+
+        html.push_str(&format!(r###"<li{}><a href="{}">{}{}</a>{}</li>"###,
+            if info.numbering.is_empty() { r###" class="unnumbered""### } else { "" },
+            state.url_of_id(domain, id, trace)?,
+            if !info.numbering.is_empty() && state.hsection_render_number[level] { format!(r###"<span class="toc_number">{}</span> "###, info.numbering) } else { String::new() },
+            info.title,
+            subsections,
+        ));
+    }
+    html.push_str("</ol>");
+
+    return Ok(html);
+}
+
+// The html (or TeX in math mode) that `§cref` produces for the given id, its links are traced to the macro at `trace`.
+fn cref(state: &State, domain: &str, id: &str, label: Option<String>, mathmode: bool, trace: &Trace, id_trace: &Trace) -> Result<String, ExpansionError> {
+    match state.sticky_state.ids.get(id) {
+        None => return Err(ExpansionError::UnknownId(id_trace.clone())),
+        Some(info) => {
+            let url = state.url_of_id(domain, id, trace)?;
+
+            match info.kind {
+                CrefKind::HSection => {
+                    let hsection_info = state.sticky_state.hsections.get(id).unwrap();
+                    let label = match &label {
+                        Some(label) => label.clone(),
+                        None => format!("{}&nbsp;{}", hsection_info.name, hsection_info.numbering),
+                    };
+                    let tag = format!(
+                        r###"<a class="ref" href="{}">{}</a>"###,
+                        url,
+                        label,
+                    );
+                    return Ok(tag);
+                }
+
+                CrefKind::Case => {
+                    let numbering = state.sticky_state.cases.get(id).unwrap();
+                    let label = match &label {
+                        Some(label) => label.clone(),
+                        None => format!("Case&nbsp;{}", numbering),
+                    };
+                    let tag = format!(
+                        r###"<a class="ref" href="{}">{}</a>"###,
+                        url,
+                        label,
+                    );
+                    return Ok(tag);
+                }
+
+                CrefKind::Box => {
+                    let box_info = state.sticky_state.boxes.get(id).unwrap();
+                    match box_info.kind {
+                        BoxKind::Proof => {
+                            let claim_name = state.claim_name(id, id_trace.clone())?;
+                            let tag = format!(
+                                r###"<a class="ref {}" href="{}" data-preview="{}">proof of {}</a>"###,
+                                box_info.kind.class(),
+                                url,
+                                state.traced_url(preview_url(domain, id), trace),
+                                claim_name,
+                            );
+                            return Ok(tag);
+                        }
+                        _ => {
+                            if mathmode {
+                                let label = match &label {
+                                    Some(label) => label.clone(),
+                                    None => format!("{}~{}", box_info.name, box_info.numbering),
+                                };
+
+                                let tex = format!(
+                                    r###"\href{{{}}}{{\htmlClass{{ref {}}}{{\htmlData{{preview={}, width={}}}{{{}}}}}}}"###,
+                                    url,
+                                    box_info.kind.class(),
+                                    state.traced_url(preview_url(domain, id), trace),
+                                    box_info.classes,
+                                    label,
+                                );
+                                return Ok(tex);
+                            } else {
+                                let label = match &label {
+                                    Some(label) => label.clone(),
+                                    None => format!("{}&nbsp;{}", box_info.name, box_info.numbering),
+                                };
+
+                                let tag = format!(
+                                    r###"<a class="ref {}" href="{}" data-preview="{}" data-width="{}">{}</a>"###,
+                                    box_info.kind.class(),
+                                    url,
+                                    state.traced_url(preview_url(domain, id), trace),
+                                    box_info.classes,
+                                    label,
+                                );
+                                return Ok(tag);
+                            }
+                        }
+                    }
+                }
+
+                CrefKind::BoxlessDefinition => return Err(ExpansionError::CrefBoxlessDefinition(id_trace.clone())),
+            }
+        }
+    }
+}
+
+// The singular or plural under which the given define was registered.
+fn defined_name(state: &State, resolver: &mut Resolver, id: &str, capitalize: bool, pluralize: bool, id_trace: &Trace) -> Result<String, ExpansionError> {
+    match state.sticky_state.defined.get(id) {
+        None => return Err(ExpansionError::UnknownDefine(id_trace.clone())),
+        Some(info) => {
+            let tmp = if pluralize {
+                &info.plural
+            } else {
+                &info.singular
+            };
+            if capitalize {
+                // The first letter might be hidden behind a placeholder.
+                return Ok(some_kind_of_uppercase_first_letter(&resolver.resolve(state, tmp)?));
+            } else {
+                return Ok(tmp.to_string());
+            }
+        }
+    }
+}
+
+// The html (or TeX in math mode) that `§r` and friends produce for the given define.
+fn reference_defined(state: &State, id: &str, name: String, fakedef: bool, mathmode: bool, id_trace: &Trace) -> Result<String, ExpansionError> {
+    match state.sticky_state.defined.get(id) {
+        None => return Err(ExpansionError::UnknownDefine(id_trace.clone())),
+        Some(info) => {
+            if fakedef {
+                return Ok(format!(
+                    r###"<dfn id="{}"><a href="{}">{}</a></dfn>"###,
+                    id,
+                    state.resolve_defined_to_preview_url(id, id_trace.clone())?,
+                    name,
+                ));
+            } else {
+                if mathmode {
+                    return Ok(format!(
+                        r###"\href{{{}}}{{\htmlClass{{ref definition}}{{\htmlData{{preview={}}}{{{}}}}}}}"###,
+                        info.href,
+                        state.resolve_defined_to_preview_url(id, id_trace.clone())?,
+                        name,
+                    ));
+                } else {
+                    return Ok(format!(
+                        r###"<a class="ref definition" href="{}" data-preview="{}">{}</a>"###,
+                        info.href,
+                        state.resolve_defined_to_preview_url(id, id_trace.clone())?,
+                        name,
+                    ));
+                }
+            }
+        }
+    }
+}
+
 // https://stackoverflow.com/a/38406885
 fn some_kind_of_uppercase_first_letter(s: &str) -> String {
     let mut c = s.chars();
@@ -2097,3 +2237,40 @@ impl Default for RTag {
         RTag(["".to_string()])
     }
 }
+
+#[derive(Deserialize, Clone)]
+pub struct DefMacro(String, usize /* number of arguments */);
+
+impl Default for DefMacro {
+    fn default() -> Self {
+        DefMacro("".to_string(), 0)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct UserMacro(Vec<String>);
+
+impl Default for UserMacro {
+    fn default() -> Self {
+        UserMacro(Vec::new())
+    }
+}
+
+#[derive(Deserialize, Clone)]
+// How many levels of hsections to list, zero for all of them.
+pub struct Toc([usize; 1]);
+
+impl Default for Toc {
+    fn default() -> Self {
+        Toc([0])
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MacroArgument([usize; 1]);
+
+impl Default for MacroArgument {
+    fn default() -> Self {
+        MacroArgument([0])
+    }
+}
@@ -1,12 +1,15 @@
-use sourcefile::SourceFile;
 use thiserror::Error;
 use ropey::Rope;
 use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::rc::Rc;
 
 use atm_parser_helper::{Eoi, ParserHelper};
 use valuable_value::human::{VVDeserializer, Error as VVError};
 
-use crate::{Yatt, print_trace, BoxKind};
+use crate::{Yatt, BoxKind};
+use crate::diagnostic::Diagnostic;
 use crate::macros::{OutInternal, Trace};
 use crate::set_examples::{S1, S2, S3, Operator, Term};
 use Operator::*;
@@ -21,25 +24,36 @@ pub(crate) enum ParseError {
     #[error("never printed")]
     Eoi,
     #[error("never printed")]
+    Unclosed(OpenParen, Option<(Trace /* `)` */, OpenParen /* what it closed */)>),
+    #[error("never printed")]
     Parameters(VVError, Trace),
     #[error("never printed")]
     UnknownMacroName(Trace),
 }
 
 impl ParseError {
-    pub fn print_parse_error(&self, source: &SourceFile) {
+    pub fn diagnostic(&self) -> Diagnostic {
         match self {
-            ParseError::Eoi => {
-                println!("Unexpected end of input.");
-            }
-            ParseError::UnknownMacroName(t) => {
-                print!("Unknown macro name.");
-                print_trace(t.clone(), source, false);
+            ParseError::Eoi => return Diagnostic::new("Eoi", "Unexpected end of input."),
+            ParseError::Unclosed(open, misplaced) => {
+                let message = match &open.macro_name {
+                    Some(name) => format!("Unexpected end of input, an argument of `§{}` is never closed.", name),
+                    None => "Unexpected end of input, a parenthesis is never closed.".to_string(),
+                };
+                let mut d = Diagnostic::new("Unclosed", message).at(open.trace());
+
+                if let Some((close, closed)) = misplaced {
+                    let hint = match &closed.macro_name {
+                        Some(name) => format!("Hint: this `)` closes an argument of `§{}`, but is indented less than where that argument starts. Its own `)` might be missing", name),
+                        None => "Hint: this `)` closes a parenthesis, but is indented less than where that parenthesis was opened. Its own `)` might be missing".to_string(),
+                    };
+                    d = d.labeled(hint, close.clone());
+                }
+                return d;
             }
+            ParseError::UnknownMacroName(t) => return Diagnostic::new("UnknownMacroName", "Unknown macro name.").at(t.clone()),
             ParseError::Parameters(e, t) => {
-                print!("Could not parse macro parameters.");
-                print_trace(t.clone(), source, true);
-                println!("\n{}", e);
+                return Diagnostic::new("Parameters", "Could not parse macro parameters.").note(format!("\n{}", e)).at(t.clone());
             }
         }
     }
@@ -54,15 +68,77 @@ impl Eoi for ParseError {
 pub(crate) fn parse(s: &str, y: &mut Yatt, source_offset: Offset) -> Result<OutInternal, ParseError> {
     let mut p = Parser {
         p: ParserHelper::new(s.as_bytes()),
+        source_offset,
+        macro_name: String::new(),
+        open_parens: Vec::new(),
+        misplaced_close: None,
     };
     return p.parse(y, source_offset);
 }
 
+/// An opening parenthesis that has not been closed yet.
+#[derive(Clone, Debug)]
+pub(crate) struct OpenParen {
+    offset: Offset,
+    macro_name: Option<String>, // `None` for parentheses in text
+    indentation: usize, // of the line it is on
+}
+
+impl OpenParen {
+    // An argument of the given macro, for callers that do not track indentation.
+    pub fn argument(offset: Offset, macro_name: &str) -> Self {
+        return OpenParen {
+            offset,
+            macro_name: Some(macro_name.to_string()),
+            indentation: 0,
+        };
+    }
+
+    pub fn trace(&self) -> Trace {
+        return Trace(Some((self.offset, self.offset + 1)));
+    }
+}
+
 struct Parser<'a> {
     p: ParserHelper<'a>,
+    source_offset: Offset, // of the start of the input
+    macro_name: String, // of the macro whose arguments are about to be parsed
+    open_parens: Vec<OpenParen>, // innermost last
+    // The first `)` on a line of its own that was indented less than the line of the `(` it closed. If the input turns
+    // out to be missing a `)`, this is a good guess for where.
+    misplaced_close: Option<(Trace, OpenParen)>,
 }
 
 impl<'a> Parser<'a> {
+    // The indentation of the line containing the given position, and whether there is only whitespace before the position on that line.
+    fn indentation(&self, position: usize) -> (usize, bool) {
+        let before = self.p.slice(..position);
+        let line = match before.iter().rposition(|b| *b == b'\n') {
+            Some(i) => &before[i + 1..],
+            None => before,
+        };
+        let indentation = line.iter().take_while(|b| **b == b' ' || **b == b'\t').count();
+        return (indentation, indentation == line.len());
+    }
+
+    fn open_paren(&mut self, position: usize, macro_name: Option<String>) {
+        self.open_parens.push(OpenParen {
+            offset: self.source_offset + position,
+            macro_name,
+            indentation: self.indentation(position).0,
+        });
+    }
+
+    fn close_paren(&mut self, position: usize) {
+        if let Some(open) = self.open_parens.pop() {
+            let (indentation, own_line) = self.indentation(position);
+            if own_line && indentation < open.indentation && self.misplaced_close.is_none() {
+                let offset = self.source_offset + position;
+                self.misplaced_close = Some((Trace(Some((offset, offset + 1))), open));
+            }
+        }
+    }
+
     fn parse(&mut self, y: &mut Yatt, source_offset: Offset) -> Result<OutInternal, ParseError> {
         let initial_position = self.p.position();
 
@@ -91,6 +167,12 @@ impl<'a> Parser<'a> {
 
         loop {
             if self.p.rest().len() == 0 {
+                // Parentheses in top-level text need not be balanced, but macro arguments must be closed.
+                if self.open_parens.iter().any(|open| open.macro_name.is_some()) {
+                    let open = self.open_parens.pop().unwrap();
+                    return Err(ParseError::Unclosed(open, self.misplaced_close.take()));
+                }
+
                 let text = unsafe { std::str::from_utf8_unchecked(self.p.slice(start..last_non_ws)) };
                 rope.insert(rope.len_chars(), text);
                 let trace = Trace(Some((source_offset, source_offset + self.p.position() - initial_position)));
@@ -126,7 +208,8 @@ impl<'a> Parser<'a> {
                         }
                     }
                 } else {
-                    let trace_start = source_offset + self.p.position() - initial_position;
+                    // Traces of macros include the `§`.
+                    let trace_start = source_offset + self.p.position() - "§".len() - initial_position;
                     let start_macro_name = self.p.position();
 
                     loop {
@@ -151,640 +234,26 @@ impl<'a> Parser<'a> {
                     let parse_parameters = self.p.rest().starts_with("[".as_bytes()) || self.p.rest().starts_with("{".as_bytes());
                     let macro_name = self.p.slice(start_macro_name..self.p.position());
 
+                    self.macro_name = String::from_utf8_lossy(macro_name).to_string();
+
                     if macro_name == b"" {
                         self.pm(OutInternal::EmptyMacro, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
 
                         self.p.advance(1);
                         start = self.p.position();
-                    } else if macro_name == b"html" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "html".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"style" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "style".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"title" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "title".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"body" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "body".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"head" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "head".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"aside" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "aside".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"footer" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "footer".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"header" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "header".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"h1" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "h1".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"h2" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "h2".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"h3" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "h3".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"h4" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "h4".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"h5" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "h5".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"h6" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "h6".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"main" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "main".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"nav" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "nav".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"section" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "section".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"blockquote" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "blockquote".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"figcaption" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "figcaption".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    // } else if macro_name == b"figure" {
-                    //     self.pm(|t, p, a| OutInternal::HtmlTag(t, "figure".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"pre" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "pre".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"div" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "div".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"p" {
-                        self.pm(|t, p, a| OutInternal::P(t, p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"li" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "li".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"ul" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "ul".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"ol" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "ol".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"button" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "button".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"a" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "a".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"abbr" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "abbr".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"code" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "code".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"dfn" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "dfn".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"html_cite" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "cite".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"em" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "em".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"i" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "i".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"b" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "b".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"q" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "q".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"s" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "s".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"span" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "span".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"strong" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "strong".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"script" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "script".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"table" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "table".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"thead" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "thead".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"tbody" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "tbody".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"tr" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "tr".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"th" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "th".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"td" {
-                        self.pm(|t, p, a| OutInternal::HtmlTag(t, "td".to_string(), p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"input" {
-                        self.pm(OutInternal::Input, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"output" {
-                        self.pm(|t, p, a| OutInternal::Output(t, p, a, false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"output_tee" {
-                        self.pm(|t, p, a| OutInternal::Output(t, p, a, true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"copy" {
-                        self.pm(OutInternal::CopyAll, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"template" {
-                        self.pm(OutInternal::Template, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"hsection" {
-                        self.pm(|t, p, a| OutInternal::HSection(t, p, a, false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"hsection*" {
-                        self.pm(|t, p, a| OutInternal::HSection(t, p, a, true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"drop" {
-                        self.pm(|t, p, a| OutInternal::Drop(t, p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"side" {
-                        self.pm(|t, p, a| OutInternal::Aside(t, p, a, false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"side*" {
-                        self.pm(|t, p, a| OutInternal::Aside(t, p, a, true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"pretty_venn_duration" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"60s"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"lorem" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, LOREM), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"meta1" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"<i class="bgclll1 highlight low">bla</i>"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"meta2" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"<i class="bgclll3 highlight low">blubb</i>"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"meta3" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"<i class="bgclll5 highlight low">blip</i>"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$mid" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"\mid"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$top" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"\top"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$bot" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"\bot"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$neg" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"\neg"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$land" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"\land"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"symbol0" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"<span class="symbol_container">/span>"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$symbol0" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"\htmlClass{symbol_container}{\char"e904}"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"symbol1" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"<span class="symbol_container"></span>"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$symbol1" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"\htmlClass{symbol_container}{\char"e903}"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"symbol2" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"<span class="symbol_container"></span>"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$symbol2" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"\htmlClass{symbol_container}{\char"e902}"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"symbol3" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"<span class="symbol_container"></span>"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$symbol3" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"\htmlClass{symbol_container}{\char"e901}"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"symbol4" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"<span class="symbol_container"></span>"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$symbol4" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, r###"\htmlClass{symbol_container}{\char"e900}"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"hr" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, "<hr>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"br" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, "<br>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$ldots" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, "\\ldots"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"euler_svg" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, EULER_SVG), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"euler_svg_union" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, EULER_SVG_UNION), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"euler_svg_setminus" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, EULER_SVG_SETMINUS), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"euler_svg_equality" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, EULER_SVG_EQUALITY), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"euler_svg_intersection" {
-                        self.pm(|t, p, a| OutInternal::Const(t, p, a, EULER_SVG_INTERSECTION), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"euler_toggles" {
-                        self.pm(OutInternal::EulerToggles, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"euler_toggles_power" {
-                        self.pm(OutInternal::EulerTogglesPower, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"cref" {
-                        self.pm(OutInternal::Cref, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"cases" {
-                        self.pm(OutInternal::Cases, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"case" {
-                        self.pm(OutInternal::Case, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"chapternav" {
-                        self.pm(OutInternal::ChapterNav, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$" {
-                        self.pm(|t, p, a| OutInternal::TeX(t, p, a, false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$$" {
-                        self.pm(|t, p, a| OutInternal::TeX(t, p, a, true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"fleqn" {
-                        self.pm(|t, p, a| OutInternal::Fleqn(t, p, a), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"cwd" {
-                        self.pm(OutInternal::Cwd, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"set_domain" {
-                        self.pm(OutInternal::SetDomain, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"definition" {
-                        self.pm(|t, p, a| OutInternal::Box(t, p, a, BoxKind::definition(), "Definition".to_string(), false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"definition*" {
-                        self.pm(|t, p, a| OutInternal::Box(t, p, a, BoxKind::definition(), "Definition".to_string(), true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"example" {
-                        self.pm(|t, p, a| OutInternal::Box(t, p, a, BoxKind::example(), "Example".to_string(), false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"figure" {
-                        self.pm(|t, p, a| OutInternal::Box(t, p, a, BoxKind::example(), "Figure".to_string(), false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"exercise" {
-                        self.pm(|t, p, a| OutInternal::Box(t, p, a, BoxKind::exercise(), "Exercise".to_string(), false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"statement" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Statement".to_string(), false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"observation" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Observation".to_string(), false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"theorem" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Theorem".to_string(), false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"lemma" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Lemma".to_string(), false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"corollary" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Corollary".to_string(), false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"conjecture" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Conjecture".to_string(), false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"falsehood" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Falsehood".to_string(), false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"statement*" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Statement".to_string(), true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"observation*" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Observation".to_string(), true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"theorem*" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Theorem".to_string(), true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"lemma*" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Lemma".to_string(), true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"corollary*" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Corollary".to_string(), true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"conjecture*" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Conjecture".to_string(), true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"falsehood*" {
-                        self.pm(|t, p, a| OutInternal::Fact(t, p, a, "Falsehood".to_string(), true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"proof" {
-                        self.pm(OutInternal::Proof, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"define" {
-                        self.pm(|t, p, a| OutInternal::Define(t, p, a, false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"definex" {
-                        self.pm(|t, p, a| OutInternal::Define(t, p, a, true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"proof_part" {
-                        self.pm(OutInternal::ProofPart, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"css_colors" {
-                        self.pm(OutInternal::CssColors, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"powerset_colors" {
-                        self.pm(OutInternal::PowersetColors, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"r" {
-                        self.pm(|t, p, a| OutInternal::ReferenceDefined(t, p, a, false, false, false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"R" {
-                        self.pm(|t, p, a| OutInternal::ReferenceDefined(t, p, a, true, false, false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"rs" {
-                        self.pm(|t, p, a| OutInternal::ReferenceDefined(t, p, a, false, true, false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"Rs" {
-                        self.pm(|t, p, a| OutInternal::ReferenceDefined(t, p, a, true, true, false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"rdef" {
-                        self.pm(|t, p, a| OutInternal::ReferenceDefined(t, p, a, false, false, true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"Rdef" {
-                        self.pm(|t, p, a| OutInternal::ReferenceDefined(t, p, a, true, false, true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"rsdef" {
-                        self.pm(|t, p, a| OutInternal::ReferenceDefined(t, p, a, false, true, true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"Rsdef" {
-                        self.pm(|t, p, a| OutInternal::ReferenceDefined(t, p, a, true, true, true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"base_case" {
-                        self.pm(|t, p, a| OutInternal::TitledList(t, p, a, r###"Base case:"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"base_cases" {
-                        self.pm(|t, p, a| OutInternal::TitledList(t, p, a, r###"Base cases:"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"inductive_case" {
-                        self.pm(|t, p, a| OutInternal::TitledList(t, p, a, r###"Inductive case:"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"inductive_cases" {
-                        self.pm(|t, p, a| OutInternal::TitledList(t, p, a, r###"Inductive cases:"###), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"proven_fact" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"<div class="proven_fact">"###, "</div>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"clfx" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"<div class="clfx">"###, "</div>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"lparen" {
-                        self.pm(|t, p, a| OutInternal::LeftDelimiter(t, p, a, "("), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"rparen" {
-                        self.pm(|t, p, a| OutInternal::RightDelimiter(t, p, a, ")"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"lquote" {
-                        self.pm(|t, p, a| OutInternal::LeftDelimiter(t, p, a, "“"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"rquote" {
-                        self.pm(|t, p, a| OutInternal::RightDelimiter(t, p, a, "”"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"parens" {
-                        self.pm(|t, p, a| OutInternal::TextDelimiters(t, p, a, "(", ")"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"verbatim" {
-                        self.pm(|t, p, a| OutInternal::TextDelimiters(t, p, a, "“", "”"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$value" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\mathrm{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$cancel" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\cancel{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$bcancel" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\bcancel{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$xcancel" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\xcancel{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$sout" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\sout{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$cancel_symbol" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"\htmlClass{cancel_symbol}{"###, "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$define_notation" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"\htmlClass{define_notation}{"###, "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$text_color" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"\htmlClass{text_color}{"###, "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"nowrap" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="nowrap">"###, "</span>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"video_container" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"<div class="video_container">"###, "</div>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"solution" {
-                        self.pm(|t, p, a| OutInternal::Toggled(t, p, a, r###"Show Solution"###, "Hide Solution"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"proof_as_exercise" {
-                        self.pm(|t, p, a| OutInternal::Toggled(t, p, a, r###"Show Proof"###, "Hide Proof"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"nobr" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="nobr">"###, "</span>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$tag" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\tag{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$p" {
-                        self.pm(OutInternal::MathGroupingParens, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$text" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\text{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"highlightlow1" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="bgclll1 highlight low">"###, "</span>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlightlow1" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll1 highlight low}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlighttop1" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll1 highlight top}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"highlightlow2" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="bgclll2 highlight low">"###, "</span>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlightlow2" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll2 highlight low}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlighttop2" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll2 highlight top}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"highlightlow3" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="bgclll3 highlight low">"###, "</span>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlightlow3" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll3 highlight low}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlighttop3" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll3 highlight top}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"highlightlow4" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="bgclll4 highlight low">"###, "</span>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlightlow4" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll4 highlight low}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlighttop4" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll4 highlight top}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"highlightlow5" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="bgclll5 highlight low">"###, "</span>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlightlow5" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll5 highlight low}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlighttop5" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll5 highlight top}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"highlightlow6" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="bgclll6 highlight low">"###, "</span>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlightlow6" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll6 highlight low}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlighttop6" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll6 highlight top}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlightlowr1" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll1 highlight rightspace low}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlighttopr1" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll1 highlight rightspace top}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlightlowr2" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll2 highlight rightspace low}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlighttopr2" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll2 highlight rightspace top}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlightlowr3" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll3 highlight rightspace low}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlighttopr3" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll3 highlight rightspace top}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlightlowr4" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll4 highlight rightspace low}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlighttopr4" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll4 highlight rightspace top}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlightlowr5" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll5 highlight rightspace low}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlighttopr5" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll5 highlight rightspace top}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlightlowr6" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll6 highlight rightspace low}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlighttopr6" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll6 highlight rightspace top}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlight1" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmclll1}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlight2" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmclll2}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlight3" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmclll3}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlight4" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmclll4}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlight5" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmclll5}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlight6" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmclll6}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlight1_direct" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmcllldirect1}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlight2_direct" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmcllldirect2}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlight3_direct" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmcllldirect3}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlight4_direct" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmcllldirect4}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlight5_direct" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmcllldirect5}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$highlight6_direct" {
-                        self.pm(|t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmcllldirect6}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$class" {
-                        self.pm(|t, p, a| OutInternal::Enclose2(t, p, a, "\\htmlClass{", "}{", "}"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"fact_marginalia" {
-                        self.pm(|t, p, a| OutInternal::Enclose2(t, p, a, r###"<div class="box_marginalia fact clfx slightlywide"><span class="aside">"###, "</span>", "</div>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"exercise_marginalia" {
-                        self.pm(|t, p, a| OutInternal::Enclose2(t, p, a, r###"<div class="box_marginalia exercise clfx slightlywide"><span class="aside">"###, "</span>", "</div>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"definition_marginalia" {
-                        self.pm(|t, p, a| OutInternal::Enclose2(t, p, a, r###"<div class="box_marginalia definition clfx slightlywide"><span class="aside">"###, "</span>", "</div>"), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"link" {
-                        self.pm(OutInternal::Link, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"captioned" {
-                        self.pm(OutInternal::Captioned, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"set_math_id" {
-                        self.pm(OutInternal::SetMathId, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"set_tag" {
-                        self.pm(|t, p, a| OutInternal::SetTag(t, p, a, false), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"set_tagc" {
-                        self.pm(|t, p, a| OutInternal::SetTag(t, p, a, true), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"rtag" {
-                        self.pm(OutInternal::RTag, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$set" {
-                        self.pm(OutInternal::MathSet, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$set_builder" {
-                        self.pm(OutInternal::MathSetBuilder, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$$align*" {
-                        self.pm(|t, p, a| OutInternal::MathEnv(t, p, a, "align*".into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$eq" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "eq".into(), r###"="###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$neq" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "neq".into(), r###"\neq"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$leq" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "leq".into(), r###"\leq"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$implies" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "implies".into(), r###"\implies"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$impliedby" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "impliedby".into(), r###"\impliedby"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$iff" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "iff".into(), r###"\iff"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$in" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "in".into(), r###"\in"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$notin" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "notin".into(), r###"\notin"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$seq" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "seq".into(), r###"="###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$sneq" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "sneq".into(), r###"\neq"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$defeq" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "defeq".into(), r###"\coloneqq"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$eqdef" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "eqdef".into(), r###"\eqqcolon"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$subseteq" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "subseteq".into(), r###"\subseteq"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$subset" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "subset".into(), r###"\subset"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$supseteq" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "supseteq".into(), r###"\supseteq"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$supset" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "supset".into(), r###"\supset"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$nsubseteq" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "nsubseteq".into(), r###"\nsubseteq"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$nsupseteq" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "nsupseteq".into(), r###"\nsupseteq"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$nsubset" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "nsubset".into(), r###"\not\subset"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$nsupset" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "nsupset".into(), r###"\not\supset"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$intersection" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "intersection".into(), r###"\cap"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$union" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "union".into(), r###"\cup"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$setminus" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "setminus".into(), r###"\setminus"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$symdif" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "symdif".into(), r###"\operatorname{\triangle}"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$lamps" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "lamps".into(), r###"\mathcal{L}"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$lamp_blue" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "lamp_blue".into(), r###"\top"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$lamp_pink" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "lamp_pink".into(), r###"\bot"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$lamp_invert" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "lamp_invert".into(), r###"\neg"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$lamp_amplify" {
-                        self.pm(|t, p, a| OutInternal::MathMacro(t, p, a, "lamp_amplify".into(), r###"\land"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$twice" {
-                        self.pm(|t, p, a| OutInternal::EncloseFunctionApplication(t, p, a, "twice".into(), r###"\operatorname{twice}"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"$powerset" {
-                        self.pm(|t, p, a| OutInternal::EncloseFunctionApplication(t, p, a, "powerset".into(), r###"\operatorname{\mathcal{P}}"###.into()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"venn_associative_intersection" {
-                        self.pm(|t, p, a| OutInternal::EquationVenn3(t, p, a,
-                            Term::Binary(
-                                Box::new(Term::Binary(
-                                    Box::new(Term::Unary(S1)),
-                                    Intersection,
-                                    Box::new(Term::Unary(S2)),
-                                )),
-                                Intersection,
-                                Box::new(Term::Unary(S3)),
-                            ),
-                            Term::Binary(
-                                Box::new(Term::Unary(S1)),
-                                Intersection,
-                                Box::new(Term::Binary(
-                                    Box::new(Term::Unary(S2)),
-                                    Intersection,
-                                    Box::new(Term::Unary(S3)),
-                                )),
-                            ),
-                        ), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"venn_associative_union" {
-                        self.pm(|t, p, a| OutInternal::EquationVenn3(t, p, a,
-                            Term::Binary(
-                                Box::new(Term::Binary(
-                                    Box::new(Term::Unary(S1)),
-                                    Union,
-                                    Box::new(Term::Unary(S2)),
-                                )),
-                                Union,
-                                Box::new(Term::Unary(S3)),
-                            ),
-                            Term::Binary(
-                                Box::new(Term::Unary(S1)),
-                                Union,
-                                Box::new(Term::Binary(
-                                    Box::new(Term::Unary(S2)),
-                                    Union,
-                                    Box::new(Term::Unary(S3)),
-                                )),
-                            ),
-                        ), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"venn_absorption_intersection_union" {
-                        self.pm(|t, p, a| OutInternal::EquationVenn2(t, p, a,
-                            Term::Binary(
-                                Box::new(Term::Unary(S1)),
-                                Intersection,
-                                Box::new(Term::Binary(
-                                    Box::new(Term::Unary(S1)),
-                                    Union,
-                                    Box::new(Term::Unary(S2)),
-                                )),
-                            ),
-                            Term::Binary(
-                                Box::new(Term::Unary(S1)),
-                                Union,
-                                Box::new(Term::Binary(
-                                    Box::new(Term::Unary(S1)),
-                                    Intersection,
-                                    Box::new(Term::Unary(S2)),
-                                )),
-                            ),
-                        ), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"venn_intersection_via_set_difference" {
-                        self.pm(|t, p, a| OutInternal::EquationVenn2(t, p, a,
-                            Term::Binary(
-                                Box::new(Term::Unary(S1)),
-                                Intersection,
-                                Box::new(Term::Unary(S2)),
-                            ),
-                            Term::Binary(
-                                Box::new(Term::Unary(S1)),
-                                Difference,
-                                Box::new(Term::Binary(
-                                    Box::new(Term::Unary(S1)),
-                                    Difference,
-                                    Box::new(Term::Unary(S2)),
-                                )),
-                            ),
-                        ), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"venn_exercise_set_difference2" {
-                        self.pm(|t, p, a| OutInternal::EquationVenn3(t, p, a,
-                            Term::Binary(
-                                Box::new(Term::Unary(S3)),
-                                Difference,
-                                Box::new(Term::Binary(
-                                    Box::new(Term::Unary(S2)),
-                                    Difference,
-                                    Box::new(Term::Unary(S1)),
-                                )),
-                            ),
-                            Term::Binary(
-                                Box::new(Term::Binary(
-                                    Box::new(Term::Unary(S3)),
-                                    Intersection,
-                                    Box::new(Term::Unary(S1)),
-                                )),
-                                Union,
-                                Box::new(Term::Binary(
-                                    Box::new(Term::Unary(S3)),
-                                    Difference,
-                                    Box::new(Term::Unary(S2)),
-                                )),
-                            ),
-                        ), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"venn_intersection" {
-                        self.pm(|t, p, a| OutInternal::Venn2(t, p, a,
-                            Term::Binary(
-                                Box::new(Term::Unary(S1)),
-                                Intersection,
-                                Box::new(Term::Unary(S2)),
-                            ),
-                        ), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"venn_union" {
-                        self.pm(|t, p, a| OutInternal::Venn2(t, p, a,
-                            Term::Binary(
-                                Box::new(Term::Unary(S1)),
-                                Union,
-                                Box::new(Term::Unary(S2)),
-                            ),
-                        ), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"venn_setdifference" {
-                        self.pm(|t, p, a| OutInternal::Venn2(t, p, a,
-                            Term::Binary(
-                                Box::new(Term::Unary(S1)),
-                                Difference,
-                                Box::new(Term::Unary(S2)),
-                            ),
-                        ), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"venn_symmetric_difference" {
-                        self.pm(|t, p, a| OutInternal::Venn2(t, p, a,
-                            Term::Binary(
-                                Box::new(Term::Unary(S1)),
-                                SymmetricDifference,
-                                Box::new(Term::Unary(S2)),
-                            ),
-                        ), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"polar_x" {
-                        self.pm(OutInternal::PolarX, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
-                    } else if macro_name == b"polar_y" {
-                        self.pm(OutInternal::PolarY, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
                     } else {
-                        let trace_end = source_offset + self.p.position() - initial_position;
-                        let trace = Trace(Some((trace_start, trace_end)));
-                        return Err(ParseError::UnknownMacroName(trace));
+                        let name = String::from_utf8_lossy(macro_name).to_string();
+                        match y.macros.get(&name) {
+                            Some(constructor) => {
+                                constructor(self, y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
+                            }
+                            None => {
+                                // Not a registered macro, so it has to be defined via `§defmacro` by the time it is expanded.
+                                let trace_end = source_offset + self.p.position() - initial_position;
+                                let name_trace = Trace(Some((trace_start, trace_end)));
+                                self.pm(|t, p, a| OutInternal::UserMacro(t, p, a, name.clone(), name_trace.clone()), y, source_offset, parse_parameters, initial_position, trace_start, &mut outs, &mut start, &mut last_non_ws)?;
+                            }
+                        }
                     }
 
                     last_non_ws = self.p.position();
@@ -794,14 +263,17 @@ impl<'a> Parser<'a> {
 
                 if c == ('(' as u8) {
                     pending_parens += 1;
+                    self.open_paren(self.p.position() - 1, None);
                     last_non_ws = self.p.position();
                 } else if c == (')' as u8) {
                     pending_parens -= 1;
+                    self.close_paren(self.p.position() - 1);
 
                     if pending_parens == 0 {
                         let text = unsafe { std::str::from_utf8_unchecked(self.p.slice(start..last_non_ws)) };
                         rope.insert(rope.len_chars(), text);
-                        let trace = Trace(Some((source_offset, source_offset + self.p.position() - initial_position)));
+                        // The closing parenthesis is not part of the argument.
+                        let trace = Trace(Some((source_offset, source_offset + self.p.position() - 1 - initial_position)));
                         outs.push(OutInternal::Text(rope.clone(), trace));
                         break;
                     } else {
@@ -824,10 +296,12 @@ impl<'a> Parser<'a> {
 
     fn parse_args(&mut self, y: &mut Yatt, source_offset: Offset) -> Result<Vec<OutInternal>, ParseError> {
         let initial_position = self.p.position();
+        let macro_name = self.macro_name.clone();
         let mut outs = Vec::new();
 
         loop {
             if self.p.advance_over(b"(") {
+                self.open_paren(self.p.position() - 1, Some(macro_name.clone()));
                 outs.push(self.parse(y, source_offset + self.p.position() - initial_position)?);
             } else {
                 return Ok(outs.into());
@@ -872,6 +346,438 @@ impl<'a> Parser<'a> {
     }
 }
 
+/// The macros available to a .gen file, keyed by name.
+///
+/// `MacroRegistry::new` contains all built-in macros, further macros can be added via `register`.
+pub struct MacroRegistry {
+    macros: HashMap<String, Rc<Constructor>>,
+}
+
+type Constructor = dyn for<'a> Fn(&mut Parser<'a>, &mut Yatt, Offset, bool, usize, usize, &mut Vec<OutInternal>, &mut usize, &mut usize) -> Result<(), ParseError>;
+
+impl MacroRegistry {
+    /// A registry containing exactly the built-in macros.
+    pub fn new() -> Self {
+        let mut r = MacroRegistry {
+            macros: HashMap::new(),
+        };
+        register_builtins(&mut r);
+        return r;
+    }
+
+    /// Register a macro under the given name, replacing any previous macro of the same name.
+    ///
+    /// The parameters in `[...]` or `{...}` are deserialized into a `P`, if they are omitted, `P::default()` is used instead.
+    pub fn register<P, M>(&mut self, name: impl Into<String>, m: M)
+    where
+        P: DeserializeOwned + Default + 'static,
+        M: Fn(Trace, P, Vec<OutInternal>) -> OutInternal + 'static,
+    {
+        self.macros.insert(name.into(), Rc::new(move |parser: &mut Parser, y: &mut Yatt, source_offset, parse_parameters, initial_position, trace_start, outs: &mut Vec<OutInternal>, start: &mut usize, last_non_ws: &mut usize| {
+            return parser.pm(&m, y, source_offset, parse_parameters, initial_position, trace_start, outs, start, last_non_ws);
+        }));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        return self.macros.contains_key(name);
+    }
+
+    /// The names of all macros, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        return self.macros.keys().map(|name| name.as_str());
+    }
+
+    fn get(&self, name: &str) -> Option<Rc<Constructor>> {
+        return self.macros.get(name).cloned();
+    }
+}
+
+impl Default for MacroRegistry {
+    fn default() -> Self {
+        MacroRegistry::new()
+    }
+}
+
+fn register_builtins(r: &mut MacroRegistry) {
+    r.register("html", |t, p, a| OutInternal::HtmlTag(t, "html".to_string(), p, a));
+    r.register("style", |t, p, a| OutInternal::HtmlTag(t, "style".to_string(), p, a));
+    r.register("title", |t, p, a| OutInternal::HtmlTag(t, "title".to_string(), p, a));
+    r.register("body", |t, p, a| OutInternal::HtmlTag(t, "body".to_string(), p, a));
+    r.register("head", |t, p, a| OutInternal::HtmlTag(t, "head".to_string(), p, a));
+    r.register("aside", |t, p, a| OutInternal::HtmlTag(t, "aside".to_string(), p, a));
+    r.register("footer", |t, p, a| OutInternal::HtmlTag(t, "footer".to_string(), p, a));
+    r.register("header", |t, p, a| OutInternal::HtmlTag(t, "header".to_string(), p, a));
+    r.register("h1", |t, p, a| OutInternal::HtmlTag(t, "h1".to_string(), p, a));
+    r.register("h2", |t, p, a| OutInternal::HtmlTag(t, "h2".to_string(), p, a));
+    r.register("h3", |t, p, a| OutInternal::HtmlTag(t, "h3".to_string(), p, a));
+    r.register("h4", |t, p, a| OutInternal::HtmlTag(t, "h4".to_string(), p, a));
+    r.register("h5", |t, p, a| OutInternal::HtmlTag(t, "h5".to_string(), p, a));
+    r.register("h6", |t, p, a| OutInternal::HtmlTag(t, "h6".to_string(), p, a));
+    r.register("main", |t, p, a| OutInternal::HtmlTag(t, "main".to_string(), p, a));
+    r.register("nav", |t, p, a| OutInternal::HtmlTag(t, "nav".to_string(), p, a));
+    r.register("section", |t, p, a| OutInternal::HtmlTag(t, "section".to_string(), p, a));
+    r.register("blockquote", |t, p, a| OutInternal::HtmlTag(t, "blockquote".to_string(), p, a));
+    r.register("figcaption", |t, p, a| OutInternal::HtmlTag(t, "figcaption".to_string(), p, a));
+    // r.register("figure", |t, p, a| OutInternal::HtmlTag(t, "figure".to_string(), p, a));
+    r.register("pre", |t, p, a| OutInternal::HtmlTag(t, "pre".to_string(), p, a));
+    r.register("div", |t, p, a| OutInternal::HtmlTag(t, "div".to_string(), p, a));
+    r.register("p", |t, p, a| OutInternal::P(t, p, a));
+    r.register("li", |t, p, a| OutInternal::HtmlTag(t, "li".to_string(), p, a));
+    r.register("ul", |t, p, a| OutInternal::HtmlTag(t, "ul".to_string(), p, a));
+    r.register("ol", |t, p, a| OutInternal::HtmlTag(t, "ol".to_string(), p, a));
+    r.register("button", |t, p, a| OutInternal::HtmlTag(t, "button".to_string(), p, a));
+    r.register("a", |t, p, a| OutInternal::HtmlTag(t, "a".to_string(), p, a));
+    r.register("abbr", |t, p, a| OutInternal::HtmlTag(t, "abbr".to_string(), p, a));
+    r.register("code", |t, p, a| OutInternal::HtmlTag(t, "code".to_string(), p, a));
+    r.register("dfn", |t, p, a| OutInternal::HtmlTag(t, "dfn".to_string(), p, a));
+    r.register("html_cite", |t, p, a| OutInternal::HtmlTag(t, "cite".to_string(), p, a));
+    r.register("em", |t, p, a| OutInternal::HtmlTag(t, "em".to_string(), p, a));
+    r.register("i", |t, p, a| OutInternal::HtmlTag(t, "i".to_string(), p, a));
+    r.register("b", |t, p, a| OutInternal::HtmlTag(t, "b".to_string(), p, a));
+    r.register("q", |t, p, a| OutInternal::HtmlTag(t, "q".to_string(), p, a));
+    r.register("s", |t, p, a| OutInternal::HtmlTag(t, "s".to_string(), p, a));
+    r.register("span", |t, p, a| OutInternal::HtmlTag(t, "span".to_string(), p, a));
+    r.register("strong", |t, p, a| OutInternal::HtmlTag(t, "strong".to_string(), p, a));
+    r.register("script", |t, p, a| OutInternal::HtmlTag(t, "script".to_string(), p, a));
+    r.register("table", |t, p, a| OutInternal::HtmlTag(t, "table".to_string(), p, a));
+    r.register("thead", |t, p, a| OutInternal::HtmlTag(t, "thead".to_string(), p, a));
+    r.register("tbody", |t, p, a| OutInternal::HtmlTag(t, "tbody".to_string(), p, a));
+    r.register("tr", |t, p, a| OutInternal::HtmlTag(t, "tr".to_string(), p, a));
+    r.register("th", |t, p, a| OutInternal::HtmlTag(t, "th".to_string(), p, a));
+    r.register("td", |t, p, a| OutInternal::HtmlTag(t, "td".to_string(), p, a));
+    r.register("input", OutInternal::Input);
+    r.register("output", |t, p, a| OutInternal::Output(t, p, a, false));
+    r.register("output_tee", |t, p, a| OutInternal::Output(t, p, a, true));
+    r.register("copy", OutInternal::CopyAll);
+    r.register("template", OutInternal::Template);
+    r.register("hsection", |t, p, a| OutInternal::HSection(t, p, a, false));
+    r.register("hsection*", |t, p, a| OutInternal::HSection(t, p, a, true));
+    r.register("drop", |t, p, a| OutInternal::Drop(t, p, a));
+    r.register("defmacro", OutInternal::DefMacro);
+    r.register("arg", OutInternal::MacroArgument);
+    r.register("param", OutInternal::MacroParameter);
+    r.register("side", |t, p, a| OutInternal::Aside(t, p, a, false));
+    r.register("side*", |t, p, a| OutInternal::Aside(t, p, a, true));
+    r.register("pretty_venn_duration", |t, p, a| OutInternal::Const(t, p, a, r###"60s"###));
+    r.register("lorem", |t, p, a| OutInternal::Const(t, p, a, LOREM));
+    r.register("meta1", |t, p, a| OutInternal::Const(t, p, a, r###"<i class="bgclll1 highlight low">bla</i>"###));
+    r.register("meta2", |t, p, a| OutInternal::Const(t, p, a, r###"<i class="bgclll3 highlight low">blubb</i>"###));
+    r.register("meta3", |t, p, a| OutInternal::Const(t, p, a, r###"<i class="bgclll5 highlight low">blip</i>"###));
+    r.register("$mid", |t, p, a| OutInternal::Const(t, p, a, r###"\mid"###));
+    r.register("$top", |t, p, a| OutInternal::Const(t, p, a, r###"\top"###));
+    r.register("$bot", |t, p, a| OutInternal::Const(t, p, a, r###"\bot"###));
+    r.register("$neg", |t, p, a| OutInternal::Const(t, p, a, r###"\neg"###));
+    r.register("$land", |t, p, a| OutInternal::Const(t, p, a, r###"\land"###));
+    r.register("symbol0", |t, p, a| OutInternal::Const(t, p, a, r###"<span class="symbol_container"></span>"###));
+    r.register("$symbol0", |t, p, a| OutInternal::Const(t, p, a, r###"\htmlClass{symbol_container}{\char"e904}"###));
+    r.register("symbol1", |t, p, a| OutInternal::Const(t, p, a, r###"<span class="symbol_container"></span>"###));
+    r.register("$symbol1", |t, p, a| OutInternal::Const(t, p, a, r###"\htmlClass{symbol_container}{\char"e903}"###));
+    r.register("symbol2", |t, p, a| OutInternal::Const(t, p, a, r###"<span class="symbol_container"></span>"###));
+    r.register("$symbol2", |t, p, a| OutInternal::Const(t, p, a, r###"\htmlClass{symbol_container}{\char"e902}"###));
+    r.register("symbol3", |t, p, a| OutInternal::Const(t, p, a, r###"<span class="symbol_container"></span>"###));
+    r.register("$symbol3", |t, p, a| OutInternal::Const(t, p, a, r###"\htmlClass{symbol_container}{\char"e901}"###));
+    r.register("symbol4", |t, p, a| OutInternal::Const(t, p, a, r###"<span class="symbol_container"></span>"###));
+    r.register("$symbol4", |t, p, a| OutInternal::Const(t, p, a, r###"\htmlClass{symbol_container}{\char"e900}"###));
+    r.register("hr", |t, p, a| OutInternal::Const(t, p, a, "<hr>"));
+    r.register("br", |t, p, a| OutInternal::Const(t, p, a, "<br>"));
+    r.register("$ldots", |t, p, a| OutInternal::Const(t, p, a, "\\ldots"));
+    r.register("euler_svg", |t, p, a| OutInternal::Const(t, p, a, EULER_SVG));
+    r.register("euler_svg_union", |t, p, a| OutInternal::Const(t, p, a, EULER_SVG_UNION));
+    r.register("euler_svg_setminus", |t, p, a| OutInternal::Const(t, p, a, EULER_SVG_SETMINUS));
+    r.register("euler_svg_equality", |t, p, a| OutInternal::Const(t, p, a, EULER_SVG_EQUALITY));
+    r.register("euler_svg_intersection", |t, p, a| OutInternal::Const(t, p, a, EULER_SVG_INTERSECTION));
+    r.register("euler_toggles", OutInternal::EulerToggles);
+    r.register("euler_toggles_power", OutInternal::EulerTogglesPower);
+    r.register("cref", OutInternal::Cref);
+    r.register("cases", OutInternal::Cases);
+    r.register("case", OutInternal::Case);
+    r.register("chapternav", OutInternal::ChapterNav);
+    r.register("toc", |t, p, a| OutInternal::Toc(t, p, a, false));
+    r.register("minitoc", |t, p, a| OutInternal::Toc(t, p, a, true));
+    r.register("$", |t, p, a| OutInternal::TeX(t, p, a, false));
+    r.register("$$", |t, p, a| OutInternal::TeX(t, p, a, true));
+    r.register("fleqn", |t, p, a| OutInternal::Fleqn(t, p, a));
+    r.register("cwd", OutInternal::Cwd);
+    r.register("set_domain", OutInternal::SetDomain);
+    r.register("definition", |t, p, a| OutInternal::Box(t, p, a, BoxKind::definition(), "Definition".to_string(), false));
+    r.register("definition*", |t, p, a| OutInternal::Box(t, p, a, BoxKind::definition(), "Definition".to_string(), true));
+    r.register("example", |t, p, a| OutInternal::Box(t, p, a, BoxKind::example(), "Example".to_string(), false));
+    r.register("figure", |t, p, a| OutInternal::Box(t, p, a, BoxKind::example(), "Figure".to_string(), false));
+    r.register("exercise", |t, p, a| OutInternal::Box(t, p, a, BoxKind::exercise(), "Exercise".to_string(), false));
+    r.register("statement", |t, p, a| OutInternal::Fact(t, p, a, "Statement".to_string(), false));
+    r.register("observation", |t, p, a| OutInternal::Fact(t, p, a, "Observation".to_string(), false));
+    r.register("theorem", |t, p, a| OutInternal::Fact(t, p, a, "Theorem".to_string(), false));
+    r.register("lemma", |t, p, a| OutInternal::Fact(t, p, a, "Lemma".to_string(), false));
+    r.register("corollary", |t, p, a| OutInternal::Fact(t, p, a, "Corollary".to_string(), false));
+    r.register("conjecture", |t, p, a| OutInternal::Fact(t, p, a, "Conjecture".to_string(), false));
+    r.register("falsehood", |t, p, a| OutInternal::Fact(t, p, a, "Falsehood".to_string(), false));
+    r.register("statement*", |t, p, a| OutInternal::Fact(t, p, a, "Statement".to_string(), true));
+    r.register("observation*", |t, p, a| OutInternal::Fact(t, p, a, "Observation".to_string(), true));
+    r.register("theorem*", |t, p, a| OutInternal::Fact(t, p, a, "Theorem".to_string(), true));
+    r.register("lemma*", |t, p, a| OutInternal::Fact(t, p, a, "Lemma".to_string(), true));
+    r.register("corollary*", |t, p, a| OutInternal::Fact(t, p, a, "Corollary".to_string(), true));
+    r.register("conjecture*", |t, p, a| OutInternal::Fact(t, p, a, "Conjecture".to_string(), true));
+    r.register("falsehood*", |t, p, a| OutInternal::Fact(t, p, a, "Falsehood".to_string(), true));
+    r.register("proof", OutInternal::Proof);
+    r.register("define", |t, p, a| OutInternal::Define(t, p, a, false));
+    r.register("definex", |t, p, a| OutInternal::Define(t, p, a, true));
+    r.register("proof_part", OutInternal::ProofPart);
+    r.register("css_colors", OutInternal::CssColors);
+    r.register("powerset_colors", OutInternal::PowersetColors);
+    r.register("r", |t, p, a| OutInternal::ReferenceDefined(t, p, a, false, false, false));
+    r.register("R", |t, p, a| OutInternal::ReferenceDefined(t, p, a, true, false, false));
+    r.register("rs", |t, p, a| OutInternal::ReferenceDefined(t, p, a, false, true, false));
+    r.register("Rs", |t, p, a| OutInternal::ReferenceDefined(t, p, a, true, true, false));
+    r.register("rdef", |t, p, a| OutInternal::ReferenceDefined(t, p, a, false, false, true));
+    r.register("Rdef", |t, p, a| OutInternal::ReferenceDefined(t, p, a, true, false, true));
+    r.register("rsdef", |t, p, a| OutInternal::ReferenceDefined(t, p, a, false, true, true));
+    r.register("Rsdef", |t, p, a| OutInternal::ReferenceDefined(t, p, a, true, true, true));
+    r.register("base_case", |t, p, a| OutInternal::TitledList(t, p, a, r###"Base case:"###));
+    r.register("base_cases", |t, p, a| OutInternal::TitledList(t, p, a, r###"Base cases:"###));
+    r.register("inductive_case", |t, p, a| OutInternal::TitledList(t, p, a, r###"Inductive case:"###));
+    r.register("inductive_cases", |t, p, a| OutInternal::TitledList(t, p, a, r###"Inductive cases:"###));
+    r.register("proven_fact", |t, p, a| OutInternal::Enclose(t, p, a, r###"<div class="proven_fact">"###, "</div>"));
+    r.register("clfx", |t, p, a| OutInternal::Enclose(t, p, a, r###"<div class="clfx">"###, "</div>"));
+    r.register("lparen", |t, p, a| OutInternal::LeftDelimiter(t, p, a, "("));
+    r.register("rparen", |t, p, a| OutInternal::RightDelimiter(t, p, a, ")"));
+    r.register("lquote", |t, p, a| OutInternal::LeftDelimiter(t, p, a, "“"));
+    r.register("rquote", |t, p, a| OutInternal::RightDelimiter(t, p, a, "”"));
+    r.register("parens", |t, p, a| OutInternal::TextDelimiters(t, p, a, "(", ")"));
+    r.register("verbatim", |t, p, a| OutInternal::TextDelimiters(t, p, a, "“", "”"));
+    r.register("$value", |t, p, a| OutInternal::Enclose(t, p, a, "\\mathrm{", "}"));
+    r.register("$cancel", |t, p, a| OutInternal::Enclose(t, p, a, "\\cancel{", "}"));
+    r.register("$bcancel", |t, p, a| OutInternal::Enclose(t, p, a, "\\bcancel{", "}"));
+    r.register("$xcancel", |t, p, a| OutInternal::Enclose(t, p, a, "\\xcancel{", "}"));
+    r.register("$sout", |t, p, a| OutInternal::Enclose(t, p, a, "\\sout{", "}"));
+    r.register("$cancel_symbol", |t, p, a| OutInternal::Enclose(t, p, a, r###"\htmlClass{cancel_symbol}{"###, "}"));
+    r.register("$define_notation", |t, p, a| OutInternal::Enclose(t, p, a, r###"\htmlClass{define_notation}{"###, "}"));
+    r.register("$text_color", |t, p, a| OutInternal::Enclose(t, p, a, r###"\htmlClass{text_color}{"###, "}"));
+    r.register("nowrap", |t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="nowrap">"###, "</span>"));
+    r.register("video_container", |t, p, a| OutInternal::Enclose(t, p, a, r###"<div class="video_container">"###, "</div>"));
+    r.register("solution", |t, p, a| OutInternal::Toggled(t, p, a, r###"Show Solution"###, "Hide Solution"));
+    r.register("proof_as_exercise", |t, p, a| OutInternal::Toggled(t, p, a, r###"Show Proof"###, "Hide Proof"));
+    r.register("nobr", |t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="nobr">"###, "</span>"));
+    r.register("$tag", |t, p, a| OutInternal::Enclose(t, p, a, "\\tag{", "}"));
+    r.register("$p", OutInternal::MathGroupingParens);
+    r.register("$text", |t, p, a| OutInternal::Enclose(t, p, a, "\\text{", "}"));
+    r.register("highlightlow1", |t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="bgclll1 highlight low">"###, "</span>"));
+    r.register("$highlightlow1", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll1 highlight low}{", "}"));
+    r.register("$highlighttop1", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll1 highlight top}{", "}"));
+    r.register("highlightlow2", |t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="bgclll2 highlight low">"###, "</span>"));
+    r.register("$highlightlow2", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll2 highlight low}{", "}"));
+    r.register("$highlighttop2", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll2 highlight top}{", "}"));
+    r.register("highlightlow3", |t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="bgclll3 highlight low">"###, "</span>"));
+    r.register("$highlightlow3", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll3 highlight low}{", "}"));
+    r.register("$highlighttop3", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll3 highlight top}{", "}"));
+    r.register("highlightlow4", |t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="bgclll4 highlight low">"###, "</span>"));
+    r.register("$highlightlow4", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll4 highlight low}{", "}"));
+    r.register("$highlighttop4", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll4 highlight top}{", "}"));
+    r.register("highlightlow5", |t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="bgclll5 highlight low">"###, "</span>"));
+    r.register("$highlightlow5", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll5 highlight low}{", "}"));
+    r.register("$highlighttop5", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll5 highlight top}{", "}"));
+    r.register("highlightlow6", |t, p, a| OutInternal::Enclose(t, p, a, r###"<span class="bgclll6 highlight low">"###, "</span>"));
+    r.register("$highlightlow6", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll6 highlight low}{", "}"));
+    r.register("$highlighttop6", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll6 highlight top}{", "}"));
+    r.register("$highlightlowr1", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll1 highlight rightspace low}{", "}"));
+    r.register("$highlighttopr1", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll1 highlight rightspace top}{", "}"));
+    r.register("$highlightlowr2", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll2 highlight rightspace low}{", "}"));
+    r.register("$highlighttopr2", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll2 highlight rightspace top}{", "}"));
+    r.register("$highlightlowr3", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll3 highlight rightspace low}{", "}"));
+    r.register("$highlighttopr3", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll3 highlight rightspace top}{", "}"));
+    r.register("$highlightlowr4", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll4 highlight rightspace low}{", "}"));
+    r.register("$highlighttopr4", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll4 highlight rightspace top}{", "}"));
+    r.register("$highlightlowr5", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll5 highlight rightspace low}{", "}"));
+    r.register("$highlighttopr5", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll5 highlight rightspace top}{", "}"));
+    r.register("$highlightlowr6", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll6 highlight rightspace low}{", "}"));
+    r.register("$highlighttopr6", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgclll6 highlight rightspace top}{", "}"));
+    r.register("$highlight1", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmclll1}{", "}"));
+    r.register("$highlight2", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmclll2}{", "}"));
+    r.register("$highlight3", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmclll3}{", "}"));
+    r.register("$highlight4", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmclll4}{", "}"));
+    r.register("$highlight5", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmclll5}{", "}"));
+    r.register("$highlight6", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmclll6}{", "}"));
+    r.register("$highlight1_direct", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmcllldirect1}{", "}"));
+    r.register("$highlight2_direct", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmcllldirect2}{", "}"));
+    r.register("$highlight3_direct", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmcllldirect3}{", "}"));
+    r.register("$highlight4_direct", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmcllldirect4}{", "}"));
+    r.register("$highlight5_direct", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmcllldirect5}{", "}"));
+    r.register("$highlight6_direct", |t, p, a| OutInternal::Enclose(t, p, a, "\\htmlClass{bgmcllldirect6}{", "}"));
+    r.register("$class", |t, p, a| OutInternal::Enclose2(t, p, a, "\\htmlClass{", "}{", "}"));
+    r.register("fact_marginalia", |t, p, a| OutInternal::Enclose2(t, p, a, r###"<div class="box_marginalia fact clfx slightlywide"><span class="aside">"###, "</span>", "</div>"));
+    r.register("exercise_marginalia", |t, p, a| OutInternal::Enclose2(t, p, a, r###"<div class="box_marginalia exercise clfx slightlywide"><span class="aside">"###, "</span>", "</div>"));
+    r.register("definition_marginalia", |t, p, a| OutInternal::Enclose2(t, p, a, r###"<div class="box_marginalia definition clfx slightlywide"><span class="aside">"###, "</span>", "</div>"));
+    r.register("link", OutInternal::Link);
+    r.register("captioned", OutInternal::Captioned);
+    r.register("set_math_id", OutInternal::SetMathId);
+    r.register("set_tag", |t, p, a| OutInternal::SetTag(t, p, a, false));
+    r.register("set_tagc", |t, p, a| OutInternal::SetTag(t, p, a, true));
+    r.register("rtag", OutInternal::RTag);
+    r.register("$set", OutInternal::MathSet);
+    r.register("$set_builder", OutInternal::MathSetBuilder);
+    r.register("$$align*", |t, p, a| OutInternal::MathEnv(t, p, a, "align*".into()));
+    r.register("$eq", |t, p, a| OutInternal::MathMacro(t, p, a, "eq".into(), r###"="###.into()));
+    r.register("$neq", |t, p, a| OutInternal::MathMacro(t, p, a, "neq".into(), r###"\neq"###.into()));
+    r.register("$leq", |t, p, a| OutInternal::MathMacro(t, p, a, "leq".into(), r###"\leq"###.into()));
+    r.register("$implies", |t, p, a| OutInternal::MathMacro(t, p, a, "implies".into(), r###"\implies"###.into()));
+    r.register("$impliedby", |t, p, a| OutInternal::MathMacro(t, p, a, "impliedby".into(), r###"\impliedby"###.into()));
+    r.register("$iff", |t, p, a| OutInternal::MathMacro(t, p, a, "iff".into(), r###"\iff"###.into()));
+    r.register("$in", |t, p, a| OutInternal::MathMacro(t, p, a, "in".into(), r###"\in"###.into()));
+    r.register("$notin", |t, p, a| OutInternal::MathMacro(t, p, a, "notin".into(), r###"\notin"###.into()));
+    r.register("$seq", |t, p, a| OutInternal::MathMacro(t, p, a, "seq".into(), r###"="###.into()));
+    r.register("$sneq", |t, p, a| OutInternal::MathMacro(t, p, a, "sneq".into(), r###"\neq"###.into()));
+    r.register("$defeq", |t, p, a| OutInternal::MathMacro(t, p, a, "defeq".into(), r###"\coloneqq"###.into()));
+    r.register("$eqdef", |t, p, a| OutInternal::MathMacro(t, p, a, "eqdef".into(), r###"\eqqcolon"###.into()));
+    r.register("$subseteq", |t, p, a| OutInternal::MathMacro(t, p, a, "subseteq".into(), r###"\subseteq"###.into()));
+    r.register("$subset", |t, p, a| OutInternal::MathMacro(t, p, a, "subset".into(), r###"\subset"###.into()));
+    r.register("$supseteq", |t, p, a| OutInternal::MathMacro(t, p, a, "supseteq".into(), r###"\supseteq"###.into()));
+    r.register("$supset", |t, p, a| OutInternal::MathMacro(t, p, a, "supset".into(), r###"\supset"###.into()));
+    r.register("$nsubseteq", |t, p, a| OutInternal::MathMacro(t, p, a, "nsubseteq".into(), r###"\nsubseteq"###.into()));
+    r.register("$nsupseteq", |t, p, a| OutInternal::MathMacro(t, p, a, "nsupseteq".into(), r###"\nsupseteq"###.into()));
+    r.register("$nsubset", |t, p, a| OutInternal::MathMacro(t, p, a, "nsubset".into(), r###"\not\subset"###.into()));
+    r.register("$nsupset", |t, p, a| OutInternal::MathMacro(t, p, a, "nsupset".into(), r###"\not\supset"###.into()));
+    r.register("$intersection", |t, p, a| OutInternal::MathMacro(t, p, a, "intersection".into(), r###"\cap"###.into()));
+    r.register("$union", |t, p, a| OutInternal::MathMacro(t, p, a, "union".into(), r###"\cup"###.into()));
+    r.register("$setminus", |t, p, a| OutInternal::MathMacro(t, p, a, "setminus".into(), r###"\setminus"###.into()));
+    r.register("$symdif", |t, p, a| OutInternal::MathMacro(t, p, a, "symdif".into(), r###"\operatorname{\triangle}"###.into()));
+    r.register("$lamps", |t, p, a| OutInternal::MathMacro(t, p, a, "lamps".into(), r###"\mathcal{L}"###.into()));
+    r.register("$lamp_blue", |t, p, a| OutInternal::MathMacro(t, p, a, "lamp_blue".into(), r###"\top"###.into()));
+    r.register("$lamp_pink", |t, p, a| OutInternal::MathMacro(t, p, a, "lamp_pink".into(), r###"\bot"###.into()));
+    r.register("$lamp_invert", |t, p, a| OutInternal::MathMacro(t, p, a, "lamp_invert".into(), r###"\neg"###.into()));
+    r.register("$lamp_amplify", |t, p, a| OutInternal::MathMacro(t, p, a, "lamp_amplify".into(), r###"\land"###.into()));
+    r.register("$twice", |t, p, a| OutInternal::EncloseFunctionApplication(t, p, a, "twice".into(), r###"\operatorname{twice}"###.into()));
+    r.register("$powerset", |t, p, a| OutInternal::EncloseFunctionApplication(t, p, a, "powerset".into(), r###"\operatorname{\mathcal{P}}"###.into()));
+    r.register("venn_associative_intersection", |t, p, a| OutInternal::EquationVenn3(t, p, a,
+        Term::Binary(
+            Box::new(Term::Binary(
+                Box::new(Term::Unary(S1)),
+                Intersection,
+                Box::new(Term::Unary(S2)),
+            )),
+            Intersection,
+            Box::new(Term::Unary(S3)),
+        ),
+        Term::Binary(
+            Box::new(Term::Unary(S1)),
+            Intersection,
+            Box::new(Term::Binary(
+                Box::new(Term::Unary(S2)),
+                Intersection,
+                Box::new(Term::Unary(S3)),
+            )),
+        ),
+    ));
+    r.register("venn_associative_union", |t, p, a| OutInternal::EquationVenn3(t, p, a,
+        Term::Binary(
+            Box::new(Term::Binary(
+                Box::new(Term::Unary(S1)),
+                Union,
+                Box::new(Term::Unary(S2)),
+            )),
+            Union,
+            Box::new(Term::Unary(S3)),
+        ),
+        Term::Binary(
+            Box::new(Term::Unary(S1)),
+            Union,
+            Box::new(Term::Binary(
+                Box::new(Term::Unary(S2)),
+                Union,
+                Box::new(Term::Unary(S3)),
+            )),
+        ),
+    ));
+    r.register("venn_absorption_intersection_union", |t, p, a| OutInternal::EquationVenn2(t, p, a,
+        Term::Binary(
+            Box::new(Term::Unary(S1)),
+            Intersection,
+            Box::new(Term::Binary(
+                Box::new(Term::Unary(S1)),
+                Union,
+                Box::new(Term::Unary(S2)),
+            )),
+        ),
+        Term::Binary(
+            Box::new(Term::Unary(S1)),
+            Union,
+            Box::new(Term::Binary(
+                Box::new(Term::Unary(S1)),
+                Intersection,
+                Box::new(Term::Unary(S2)),
+            )),
+        ),
+    ));
+    r.register("venn_intersection_via_set_difference", |t, p, a| OutInternal::EquationVenn2(t, p, a,
+        Term::Binary(
+            Box::new(Term::Unary(S1)),
+            Intersection,
+            Box::new(Term::Unary(S2)),
+        ),
+        Term::Binary(
+            Box::new(Term::Unary(S1)),
+            Difference,
+            Box::new(Term::Binary(
+                Box::new(Term::Unary(S1)),
+                Difference,
+                Box::new(Term::Unary(S2)),
+            )),
+        ),
+    ));
+    r.register("venn_exercise_set_difference2", |t, p, a| OutInternal::EquationVenn3(t, p, a,
+        Term::Binary(
+            Box::new(Term::Unary(S3)),
+            Difference,
+            Box::new(Term::Binary(
+                Box::new(Term::Unary(S2)),
+                Difference,
+                Box::new(Term::Unary(S1)),
+            )),
+        ),
+        Term::Binary(
+            Box::new(Term::Binary(
+                Box::new(Term::Unary(S3)),
+                Intersection,
+                Box::new(Term::Unary(S1)),
+            )),
+            Union,
+            Box::new(Term::Binary(
+                Box::new(Term::Unary(S3)),
+                Difference,
+                Box::new(Term::Unary(S2)),
+            )),
+        ),
+    ));
+    r.register("venn_intersection", |t, p, a| OutInternal::Venn2(t, p, a,
+        Term::Binary(
+            Box::new(Term::Unary(S1)),
+            Intersection,
+            Box::new(Term::Unary(S2)),
+        ),
+    ));
+    r.register("venn_union", |t, p, a| OutInternal::Venn2(t, p, a,
+        Term::Binary(
+            Box::new(Term::Unary(S1)),
+            Union,
+            Box::new(Term::Unary(S2)),
+        ),
+    ));
+    r.register("venn_setdifference", |t, p, a| OutInternal::Venn2(t, p, a,
+        Term::Binary(
+            Box::new(Term::Unary(S1)),
+            Difference,
+            Box::new(Term::Unary(S2)),
+        ),
+    ));
+    r.register("venn_symmetric_difference", |t, p, a| OutInternal::Venn2(t, p, a,
+        Term::Binary(
+            Box::new(Term::Unary(S1)),
+            SymmetricDifference,
+            Box::new(Term::Unary(S2)),
+        ),
+    ));
+    r.register("polar_x", OutInternal::PolarX);
+    r.register("polar_y", OutInternal::PolarY);
+}
+
 static LOREM: &str = "Lorem ipsum dolor sit amet, consectetuer adipiscing elit. Aenean commodo ligula eget dolor. Aenean massa. Cum sociis natoque penatibus et magnis dis parturient montes, nascetur ridiculus mus. Donec quam felis, ultricies nec, pellentesque eu, pretium quis, sem. Nulla consequat massa quis enim.";
 
 static EULER_SVG: &str = r###"<svg version="1.1" viewBox="-100 -100 200 200" xmlns="http://www.w3.org/2000/svg" class="eulersvg">
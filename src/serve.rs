@@ -0,0 +1,169 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::{print_yatt_error, watch_with, RunConfiguration, Verbosity, Yatt};
+
+// Pages poll this path for the number of finished builds, and reload when it changes.
+const LIVE_RELOAD_PATH: &str = "/__atm_live_reload";
+
+pub(crate) const LIVE_RELOAD_SCRIPT: &str = r###"
+        <script>
+            (function() {
+                let build = null;
+                setInterval(function() {
+                    fetch("/__atm_live_reload").then(response => response.text()).then(function(current) {
+                        if (build !== null && current !== build) {
+                            location.reload();
+                        }
+                        build = current;
+                    }).catch(function() {});
+                }, 500);
+            })();
+        </script>"###;
+
+/// Serve the `build/` directory on `localhost:port`, rebuilding on every change like `watch`.
+///
+/// Unless the configuration overrides it, the domain is set to the served address. All `§template` pages reload themselves after each successful rebuild. Never returns.
+pub fn serve(mut c: RunConfiguration, port: u16) -> ! {
+    let address = format!("http://localhost:{}/", port);
+    c.domain.get_or_insert(address.clone());
+    c.live_reload = true;
+
+    let mut y = Yatt::new(c).unwrap();
+    if let Err(e) = y.configure() {
+        print_yatt_error(&e, &y.source, y.message_format);
+        std::process::exit(1);
+    }
+    let build_dir = y.state.output_dir.clone();
+
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Failed to listen on port {}:\n{}", port, e);
+            std::process::exit(1);
+        }
+    };
+    if y.verbosity >= Verbosity::Normal {
+        println!("Serving {} at {}", build_dir.to_string_lossy(), address);
+    }
+
+    let builds = Arc::new(AtomicUsize::new(0));
+    let server_builds = builds.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let build_dir = build_dir.clone();
+            let builds = server_builds.clone();
+            thread::spawn(move || {
+                let _ = respond(stream, &build_dir, &builds);
+            });
+        }
+    });
+
+    watch_with(y, |success| {
+        if success {
+            builds.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+}
+
+fn respond(mut stream: TcpStream, build_dir: &Path, builds: &AtomicUsize) -> Result<(), io::Error> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let target = parts.next().unwrap_or("/");
+    let path = percent_decode(target.split(['?', '#']).next().unwrap_or("/"));
+
+    if method != "GET" {
+        return send(&mut stream, "405 Method Not Allowed", "text/plain", b"Method not allowed");
+    }
+
+    if path == LIVE_RELOAD_PATH {
+        let body = builds.load(Ordering::SeqCst).to_string();
+        return send(&mut stream, "200 OK", "text/plain", body.as_bytes());
+    }
+
+    match resolve(build_dir, &path) {
+        Some(file) => match std::fs::read(&file) {
+            Ok(content) => return send(&mut stream, "200 OK", content_type(&file), &content),
+            Err(_) => return send(&mut stream, "404 Not Found", "text/plain", b"Not found"),
+        },
+        None => return send(&mut stream, "404 Not Found", "text/plain", b"Not found"),
+    }
+}
+
+fn send(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> Result<(), io::Error> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len(),
+    )?;
+    stream.write_all(body)?;
+    return stream.flush();
+}
+
+// Map a request path to a file inside the build directory, refusing to leave it.
+fn resolve(build_dir: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = build_dir.to_path_buf();
+    for component in Path::new(path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(c) => file.push(c),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    if file.is_dir() {
+        file.push("index.html");
+    }
+
+    if file.is_file() {
+        return Some(file);
+    } else {
+        return None;
+    }
+}
+
+pub(crate) fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(byte) = u8::from_str_radix(&String::from_utf8_lossy(&bytes[i + 1..i + 3]), 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    return String::from_utf8_lossy(&decoded).to_string();
+}
+
+fn content_type(p: &Path) -> &'static str {
+    match p.extension().and_then(|e| e.to_str()) {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,135 @@
+use std::path::{Path, PathBuf};
+
+use crate::macros::Trace;
+
+/// All source files of a build, concatenated so that a single offset identifies a position in any of them.
+#[derive(Default)]
+pub(crate) struct SourceMap {
+    pub contents: String,
+    files: Vec<SourceFileInfo>,
+}
+
+struct SourceFileInfo {
+    name: String,
+    path: PathBuf,
+    start: usize,
+    len: usize,
+    included_from: Trace, // the `§input` that read this file, `Trace(None)` for the entrypoint
+}
+
+/// A position in one of the source files, lines and columns start at one.
+pub(crate) struct Position<'a> {
+    pub filename: &'a str,
+    pub line: usize,
+    pub col: usize,
+    pub line_text: &'a str,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        return SourceMap::default();
+    }
+
+    /// Append a file, returns the offset at which its contents start.
+    pub fn add_file(&mut self, path: &Path, contents: &str, included_from: Trace) -> usize {
+        // Name files like the user would, relative to the working directory.
+        let name = match std::env::current_dir() {
+            Ok(cwd) => path.strip_prefix(cwd).unwrap_or(path).to_string_lossy().to_string(),
+            Err(_) => path.to_string_lossy().to_string(),
+        };
+
+        let start = self.contents.len();
+        self.files.push(SourceFileInfo {
+            name,
+            path: crate::normalize(path),
+            start,
+            len: contents.len(),
+            included_from,
+        });
+        self.contents.push_str(contents);
+        return start;
+    }
+
+    fn file_at(&self, offset: usize) -> Option<&SourceFileInfo> {
+        // The end of a file is also the start of the next one, which is what offsets there usually refer to.
+        return self.files.iter().rev().find(|f| f.start <= offset && offset <= f.start + f.len);
+    }
+
+    pub fn resolve(&self, offset: usize) -> Option<Position<'_>> {
+        let file = self.file_at(offset)?;
+        return Some(self.position_in(file, offset));
+    }
+
+    /// Resolve both ends of a span, within the file that contains its start.
+    pub fn resolve_span(&self, start: usize, end: usize) -> Option<(Position<'_>, Position<'_>)> {
+        let file = self.file_at(start)?;
+        let end = end.clamp(start, file.start + file.len);
+        return Some((self.position_in(file, start), self.position_in(file, end)));
+    }
+
+    /// Whether the span of `outer` contains the start of `inner`, directly or through the `§input`s that read its file.
+    pub fn contains(&self, outer: &Trace, inner: &Trace) -> bool {
+        let (start, end) = match outer.0 {
+            Some(span) => span,
+            None => return false,
+        };
+        let mut offset = match inner.0 {
+            Some((offset, _)) => offset,
+            None => return false,
+        };
+
+        loop {
+            if start <= offset && offset <= end {
+                return true;
+            }
+            match self.file_at(offset).map(|f| &f.included_from) {
+                Some(Trace(Some((input, _)))) => offset = *input,
+                _ => return false,
+            }
+        }
+    }
+
+    fn position_in<'a>(&'a self, file: &'a SourceFileInfo, offset: usize) -> Position<'a> {
+        let text = &self.contents[file.start..file.start + file.len];
+        let offset = offset - file.start;
+
+        let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = text[offset..].find('\n').map_or(text.len(), |i| offset + i);
+
+        return Position {
+            filename: &file.name,
+            line: text[..offset].matches('\n').count() + 1,
+            col: text[line_start..offset].chars().count() + 1,
+            line_text: &text[line_start..line_end],
+        };
+    }
+
+    /// The path of the file containing the given offset.
+    pub fn path_at(&self, offset: usize) -> Option<&Path> {
+        return self.file_at(offset).map(|f| f.path.as_path());
+    }
+
+    /// The offset at which the given file starts, and its contents. Files that were read several times yield their first reading.
+    pub fn file(&self, path: &Path) -> Option<(usize, &str)> {
+        let file = self.files.iter().find(|f| f.path == crate::normalize(path))?;
+        return Some((file.start, &self.contents[file.start..file.start + file.len]));
+    }
+
+    /// The `§input` macros that led to the file containing the given offset, innermost first.
+    pub fn include_stack(&self, offset: usize) -> Vec<Trace> {
+        let mut stack = Vec::new();
+        let mut current = self.file_at(offset);
+
+        while let Some(file) = current {
+            match file.included_from {
+                Trace(Some(span)) => {
+                    stack.push(file.included_from.clone());
+                    current = self.file_at(span.0);
+                }
+                Trace(None) => break,
+            }
+        }
+
+        return stack;
+    }
+}
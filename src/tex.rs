@@ -0,0 +1,184 @@
+// Rendering TeX with KaTeX dominates the build time. Expansion only records what to render, all math of a
+// build is then rendered at once, spread over all cores (each thread gets its own KaTeX engine).
+// Renderings are kept in an on-disk cache between builds.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use crate::vfs::FileSystem;
+
+/// Where the cache lives, relative to the directory of the entrypoint.
+pub const CACHE_FILE: &str = ".htmlgen-cache/katex.json";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct TexJob {
+    pub content: String,
+    pub display: bool,
+    pub fleqn: bool,
+    pub trust: bool,
+}
+
+pub(crate) fn render(job: &TexJob) -> Result<String, katex::Error> {
+    let opts = katex::Opts::builder()
+        .display_mode(job.display)
+        .throw_on_error(true)
+        .trust(job.trust)
+        .fleqn(job.fleqn)
+        .build().unwrap();
+
+    return katex::render_with_opts(&job.content, &opts);
+}
+
+/// Render all jobs, the results are in the same order as the jobs.
+pub(crate) fn render_all(jobs: &[TexJob]) -> Vec<Result<String, katex::Error>> {
+    return render_on(jobs, thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+}
+
+/// Like `render_all`, with at most the given number of threads.
+pub(crate) fn render_on(jobs: &[TexJob], threads: usize) -> Vec<Result<String, katex::Error>> {
+    let threads = threads.min(jobs.len());
+    if threads <= 1 {
+        // Keep using the engine of the current thread, which survives between rebuilds.
+        return jobs.iter().map(render).collect();
+    }
+
+    // Thread `t` renders the jobs `t`, `t + threads`, `t + 2 * threads`, ..., so that long stretches of
+    // heavy math do not all end up on the same thread.
+    let rendered: Vec<Vec<Result<String, katex::Error>>> = thread::scope(|s| {
+        let handles: Vec<_> = (0..threads).map(|t| {
+            s.spawn(move || jobs.iter().skip(t).step_by(threads).map(render).collect())
+        }).collect();
+        return handles.into_iter().map(|h| h.join().unwrap()).collect();
+    });
+
+    let mut iters: Vec<_> = rendered.into_iter().map(|r| r.into_iter()).collect();
+    return (0..jobs.len()).map(|i| iters[i % threads].next().unwrap()).collect();
+}
+
+/// Like `render_all`, but only renders what is not in the cache, and every distinct job only once.
+pub(crate) fn render_all_cached(jobs: &[TexJob], cache: &mut TexCache) -> Vec<Result<String, katex::Error>> {
+    let mut seen = HashSet::new();
+    let missing: Vec<TexJob> = jobs.iter().filter(|job| !cache.contains(job) && seen.insert(*job)).cloned().collect();
+
+    let mut errors = HashMap::new();
+    for (job, rendered) in missing.iter().zip(render_all(&missing)) {
+        match rendered {
+            Ok(html) => cache.insert(job.clone(), html),
+            Err(e) => {
+                errors.insert(job, e);
+            }
+        }
+    }
+
+    return jobs.iter().map(|job| match cache.get(job) {
+        Some(html) => Ok(html),
+        None => Err(errors[job].clone()),
+    }).collect();
+}
+
+/// The on-disk format of the cache.
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    katex_version: String,
+    renderings: Vec<CachedRendering>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedRendering {
+    tex: String,
+    display: bool,
+    fleqn: bool,
+    trust: bool,
+    html: String,
+}
+
+/// Renderings of previous builds, and those of the current build.
+///
+/// Only the renderings that the current build used are saved, so the cache does not grow with every edit.
+#[derive(Default)]
+pub(crate) struct TexCache {
+    previous: HashMap<TexJob, String>,
+    current: HashMap<TexJob, String>,
+    rendered: usize,
+}
+
+impl TexCache {
+    /// Load the cache, starting from scratch if it is missing, unreadable, or was written for another KaTeX version.
+    pub fn load(fs: &dyn FileSystem, p: &Path) -> Self {
+        let mut cache = TexCache::default();
+
+        if let Ok(s) = fs.read_to_string(p) {
+            if let Ok(file) = serde_json::from_str::<CacheFile>(&s) {
+                if file.katex_version == katex::KATEX_VERSION {
+                    cache.previous = file.renderings.into_iter().map(|r| (TexJob {
+                        content: r.tex,
+                        display: r.display,
+                        fleqn: r.fleqn,
+                        trust: r.trust,
+                    }, r.html)).collect();
+                }
+            }
+        }
+
+        return cache;
+    }
+
+    pub fn contains(&self, job: &TexJob) -> bool {
+        return self.current.contains_key(job) || self.previous.contains_key(job);
+    }
+
+    pub fn get(&mut self, job: &TexJob) -> Option<String> {
+        if let Some(html) = self.previous.remove(job) {
+            self.current.insert(job.clone(), html);
+        }
+        return self.current.get(job).cloned();
+    }
+
+    pub fn insert(&mut self, job: TexJob, html: String) {
+        self.rendered += 1;
+        self.current.insert(job, html);
+    }
+
+    /// Render a single job unless it is cached.
+    pub fn render(&mut self, job: TexJob) -> Result<String, katex::Error> {
+        if let Some(html) = self.get(&job) {
+            return Ok(html);
+        }
+
+        let html = render(&job)?;
+        self.insert(job, html.clone());
+        return Ok(html);
+    }
+
+    /// How many formulas this build had to render itself.
+    pub fn rendered(&self) -> usize {
+        return self.rendered;
+    }
+
+    /// Save the renderings of the current build, unless that would not change the cache.
+    pub fn save(&self, fs: &dyn FileSystem, p: &Path) -> Result<(), io::Error> {
+        if self.rendered == 0 && self.previous.is_empty() {
+            return Ok(());
+        }
+
+        let mut renderings: Vec<CachedRendering> = self.current.iter().map(|(job, html)| CachedRendering {
+            tex: job.content.clone(),
+            display: job.display,
+            fleqn: job.fleqn,
+            trust: job.trust,
+            html: html.clone(),
+        }).collect();
+        // Keep the file stable between identical builds.
+        renderings.sort_by(|a, b| (&a.tex, a.display, a.fleqn, a.trust).cmp(&(&b.tex, b.display, b.fleqn, b.trust)));
+
+        let file = CacheFile {
+            katex_version: katex::KATEX_VERSION.to_string(),
+            renderings,
+        };
+        return fs.write(p, &serde_json::to_string(&file)?);
+    }
+}
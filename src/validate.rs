@@ -0,0 +1,126 @@
+// An optional check that the generated html is well-formed: every element is closed in the right order, ids are
+// unique per file, and no element occurs where browsers would silently move it elsewhere, like a `div` in a `p`.
+// Problems are reported at the innermost enclosing element whose id the build knows, or else at the output itself.
+
+use std::collections::HashSet;
+
+use crate::html::{self, Tag};
+use crate::lint::Warning;
+use crate::links::{is_html, output_trace};
+use crate::State;
+
+// Elements without content, they never get closed.
+const VOID: [&str; 14] = ["area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source", "track", "wbr"];
+
+// Elements whose start tag implicitly closes an open `p`.
+const CLOSES_P: [&str; 29] = [
+    "address", "article", "aside", "blockquote", "details", "dialog", "div", "dl", "fieldset", "figcaption", "figure", "footer", "form",
+    "h1", "h2", "h3", "h4", "h5", "h6", "header", "hgroup", "hr", "main", "menu", "nav", "ol", "p", "pre", "section",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum HtmlProblem {
+    Unclosed(String),
+    Unopened(String),
+    Misnested(String /* still open */, String /* closed */),
+    DuplicateId(String),
+    InvalidNesting(String /* element */, String /* ancestor */),
+}
+
+struct Open<'a> {
+    name: &'a str,
+    id: Option<&'a str>,
+}
+
+/// All problems of the html files among the pending writes.
+pub(crate) fn check(state: &State) -> Vec<Warning> {
+    let mut warnings = vec![];
+
+    for (p, content, trace) in &state.pending_writes {
+        if !is_html(p) {
+            continue;
+        }
+
+        let tags = html::tags(content);
+        let mut open: Vec<Open> = vec![];
+        let mut ids = HashSet::new();
+        let mut report = |open: &[Open], offset: usize, problem: HtmlProblem| {
+            let known = open.iter().rev().filter_map(|o| state.sticky_state.ids.get(o.id?)).next();
+            let t = match known {
+                Some(info) => info.definition.clone(),
+                None => output_trace(state, p, trace),
+            };
+            let (line, col) = line_and_column(content, offset);
+            warnings.push(Warning::Html(t, p.clone(), line, col, problem));
+        };
+
+        for tag in &tags {
+            match tag {
+                Tag::Start { name, self_closing, offset, .. } => {
+                    if let Some(id) = tag.attribute("id") {
+                        if !ids.insert(id) {
+                            report(&open, *offset, HtmlProblem::DuplicateId(id.to_string()));
+                        }
+                    }
+
+                    if CLOSES_P.contains(&name.as_str()) && open.iter().any(|o| o.name == "p") {
+                        report(&open, *offset, HtmlProblem::InvalidNesting(name.clone(), "p".to_string()));
+                    } else if name == "a" && open.iter().any(|o| o.name == "a") {
+                        report(&open, *offset, HtmlProblem::InvalidNesting(name.clone(), "a".to_string()));
+                    }
+
+                    // Self-closing tags are only meaningful in svg and MathML, but do not open anything either way.
+                    if !VOID.contains(&name.as_str()) && !self_closing {
+                        open.push(Open { name, id: tag.attribute("id") });
+                    }
+                }
+                Tag::End { name, offset } => match open.iter().rposition(|o| o.name == name) {
+                    None => report(&open, *offset, HtmlProblem::Unopened(name.clone())),
+                    Some(i) => {
+                        if i + 1 < open.len() {
+                            let inner = open[open.len() - 1].name.to_string();
+                            report(&open, *offset, HtmlProblem::Misnested(inner, name.clone()));
+                        }
+                        open.truncate(i);
+                    }
+                },
+            }
+        }
+
+        while let Some(o) = open.last() {
+            let problem = HtmlProblem::Unclosed(o.name.to_string());
+            report(&open, content.len(), problem);
+            open.pop();
+        }
+    }
+
+    return warnings;
+}
+
+fn line_and_column(s: &str, offset: usize) -> (usize, usize) {
+    let before = &s[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    return (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1);
+}
+
+impl HtmlProblem {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HtmlProblem::Unclosed(_) => return "UnclosedTag",
+            HtmlProblem::Unopened(_) => return "UnopenedTag",
+            HtmlProblem::Misnested(..) => return "MisnestedTag",
+            HtmlProblem::DuplicateId(_) => return "DuplicateHtmlId",
+            HtmlProblem::InvalidNesting(..) => return "InvalidNesting",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            HtmlProblem::Unclosed(name) => return format!("`<{}>` is never closed.", name),
+            HtmlProblem::Unopened(name) => return format!("`</{}>` closes an element that is not open.", name),
+            HtmlProblem::Misnested(inner, name) => return format!("`</{}>` closes its element while the `<{}>` inside of it is still open.", name, inner),
+            HtmlProblem::DuplicateId(id) => return format!("The id `{}` occurs more than once in the same file.", id),
+            HtmlProblem::InvalidNesting(name, ancestor) => return format!("`<{}>` cannot occur inside of `<{}>`, browsers move it out.", name, ancestor),
+        }
+    }
+}
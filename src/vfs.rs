@@ -0,0 +1,189 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// All file access of a build goes through a `FileSystem`.
+pub trait FileSystem {
+    fn read_to_string(&self, p: &Path) -> Result<String, io::Error>;
+
+    /// Write a file, creating all missing parent directories.
+    fn write(&self, p: &Path, content: &str) -> Result<(), io::Error>;
+
+    /// Copy the file or directory `from` into the directory `to`, overwriting existing files.
+    fn copy_into(&self, from: &Path, to: &Path) -> Result<(), fs_extra::error::Error>;
+
+    /// Whether there is a file or directory at the given path.
+    fn exists(&self, p: &Path) -> bool;
+
+    /// Delete a directory and everything inside of it.
+    fn remove_dir_all(&self, p: &Path) -> Result<(), io::Error>;
+}
+
+impl<F: FileSystem + ?Sized> FileSystem for Rc<F> {
+    fn read_to_string(&self, p: &Path) -> Result<String, io::Error> {
+        return (**self).read_to_string(p);
+    }
+
+    fn write(&self, p: &Path, content: &str) -> Result<(), io::Error> {
+        return (**self).write(p, content);
+    }
+
+    fn copy_into(&self, from: &Path, to: &Path) -> Result<(), fs_extra::error::Error> {
+        return (**self).copy_into(from, to);
+    }
+
+    fn exists(&self, p: &Path) -> bool {
+        return (**self).exists(p);
+    }
+
+    fn remove_dir_all(&self, p: &Path) -> Result<(), io::Error> {
+        return (**self).remove_dir_all(p);
+    }
+}
+
+/// The actual file system.
+pub struct DiskFs;
+
+impl FileSystem for DiskFs {
+    fn read_to_string(&self, p: &Path) -> Result<String, io::Error> {
+        return std::fs::read_to_string(p);
+    }
+
+    fn write(&self, p: &Path, content: &str) -> Result<(), io::Error> {
+        let mut dirname = p.to_path_buf();
+        dirname.pop();
+        let _ = fs_extra::dir::create_all(dirname, false);
+        return std::fs::write(p, content);
+    }
+
+    fn copy_into(&self, from: &Path, to: &Path) -> Result<(), fs_extra::error::Error> {
+        // `create_all` chokes on trailing `.` components, collecting the components drops them.
+        let to: PathBuf = to.components().collect();
+        let _ = fs_extra::dir::create_all(&to, false);
+
+        let mut opts = fs_extra::dir::CopyOptions::new();
+        opts.copy_inside = true;
+        opts.overwrite = true;
+
+        return fs_extra::copy_items(&[from], &to, &opts).map(|_| ());
+    }
+
+    fn exists(&self, p: &Path) -> bool {
+        return p.exists();
+    }
+
+    fn remove_dir_all(&self, p: &Path) -> Result<(), io::Error> {
+        return std::fs::remove_dir_all(p);
+    }
+}
+
+/// A file system that only exists in memory. Clones share the same files.
+#[derive(Clone, Default)]
+pub struct MemoryFs {
+    files: Rc<RefCell<BTreeMap<PathBuf, String>>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        return MemoryFs::default();
+    }
+
+    pub fn from_files(files: impl IntoIterator<Item = (PathBuf, String)>) -> Self {
+        return MemoryFs {
+            files: Rc::new(RefCell::new(files.into_iter().collect())),
+        };
+    }
+
+    pub fn get(&self, p: &Path) -> Option<String> {
+        return self.files.borrow().get(p).cloned();
+    }
+
+    /// A snapshot of all files.
+    pub fn files(&self) -> BTreeMap<PathBuf, String> {
+        return self.files.borrow().clone();
+    }
+}
+
+impl FileSystem for MemoryFs {
+    fn read_to_string(&self, p: &Path) -> Result<String, io::Error> {
+        match self.get(p) {
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no such file in memory")),
+            Some(content) => return Ok(content),
+        }
+    }
+
+    fn write(&self, p: &Path, content: &str) -> Result<(), io::Error> {
+        self.files.borrow_mut().insert(p.to_path_buf(), content.to_string());
+        return Ok(());
+    }
+
+    fn copy_into(&self, from: &Path, to: &Path) -> Result<(), fs_extra::error::Error> {
+        let name = from.file_name().map(PathBuf::from).unwrap_or_default();
+
+        let mut copied = Vec::new();
+        for (path, content) in self.files.borrow().iter() {
+            if let Ok(rest) = path.strip_prefix(from) {
+                let target = if rest.as_os_str().is_empty() { to.join(&name) } else { to.join(&name).join(rest) };
+                copied.push((target, content.clone()));
+            }
+        }
+
+        if copied.is_empty() {
+            return Err(fs_extra::error::Error::new(fs_extra::error::ErrorKind::NotFound, "no such file in memory"));
+        }
+
+        self.files.borrow_mut().extend(copied);
+        return Ok(());
+    }
+
+    fn exists(&self, p: &Path) -> bool {
+        return self.files.borrow().keys().any(|path| path.starts_with(p));
+    }
+
+    fn remove_dir_all(&self, p: &Path) -> Result<(), io::Error> {
+        if !self.exists(p) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such directory in memory"));
+        }
+        self.files.borrow_mut().retain(|path, _| !path.starts_with(p));
+        return Ok(());
+    }
+}
+
+/// Reads from another file system, but drops all writes and deletions. Copies only check that their source exists.
+pub struct DryRunFs {
+    inner: Box<dyn FileSystem>,
+}
+
+impl DryRunFs {
+    pub fn new(inner: Box<dyn FileSystem>) -> Self {
+        return DryRunFs { inner };
+    }
+}
+
+impl FileSystem for DryRunFs {
+    fn read_to_string(&self, p: &Path) -> Result<String, io::Error> {
+        return self.inner.read_to_string(p);
+    }
+
+    fn write(&self, _p: &Path, _content: &str) -> Result<(), io::Error> {
+        return Ok(());
+    }
+
+    fn copy_into(&self, from: &Path, _to: &Path) -> Result<(), fs_extra::error::Error> {
+        if self.inner.exists(from) {
+            return Ok(());
+        } else {
+            return Err(fs_extra::error::Error::new(fs_extra::error::ErrorKind::NotFound, "source does not exist"));
+        }
+    }
+
+    fn exists(&self, p: &Path) -> bool {
+        return self.inner.exists(p);
+    }
+
+    fn remove_dir_all(&self, _p: &Path) -> Result<(), io::Error> {
+        return Ok(());
+    }
+}